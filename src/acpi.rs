@@ -0,0 +1,50 @@
+// SPDX-License-Identifier: MIT OR Apache-2.0
+//
+// Copyright (c) 2024 SUSE LLC
+//
+// Author: Roy Hopkins <roy.hopkins@suse.com>
+
+use std::error::Error;
+use std::fs;
+
+// Size of the header that is common to all ACPI system description tables.
+const ACPI_TABLE_HEADER_SIZE: usize = 36;
+
+/// Load the ACPI table files in a directory and concatenate them in order of
+/// their filenames. Each file must contain a single table whose header length
+/// matches the size of the file. An empty directory results in no tables.
+pub fn load_acpi_tables(dir: &String) -> Result<Vec<u8>, Box<dyn Error>> {
+    let mut filenames = fs::read_dir(dir)
+        .inspect_err(|_| {
+            eprintln!("Failed to open ACPI table directory {}", dir);
+        })?
+        .map(|entry| entry.map(|entry| entry.path()))
+        .collect::<Result<Vec<_>, _>>()?;
+    filenames.retain(|path| path.is_file());
+    filenames.sort();
+
+    let mut tables = Vec::new();
+    for filename in filenames {
+        let data = fs::read(&filename).inspect_err(|_| {
+            eprintln!("Failed to open ACPI table file {}", filename.display());
+        })?;
+        if data.len() < ACPI_TABLE_HEADER_SIZE {
+            return Err(format!(
+                "The ACPI table file {} is too small to contain a table header",
+                filename.display()
+            )
+            .into());
+        }
+        let length = u32::from_le_bytes(data[4..8].try_into().unwrap()) as usize;
+        if length != data.len() {
+            return Err(format!(
+                "The ACPI table file {} has size {:#X} but the table header specifies {length:#X} bytes",
+                filename.display(),
+                data.len()
+            )
+            .into());
+        }
+        tables.extend_from_slice(&data);
+    }
+    Ok(tables)
+}
@@ -4,27 +4,764 @@
 //
 // Author: Roy Hopkins <roy.hopkins@suse.com>
 
-use clap::{Parser, ValueEnum};
+use std::error::Error;
+
+use clap::{Args, Parser, Subcommand, ValueEnum};
+use igvm_defs::{
+    IgvmPlatformType, IGVM_NATIVE_PLATFORM_VERSION, IGVM_SEV_ES_PLATFORM_VERSION,
+    IGVM_SEV_PLATFORM_VERSION, IGVM_SEV_SNP_PLATFORM_VERSION, IGVM_TDX_PLATFORM_VERSION,
+    PAGE_SIZE_4K,
+};
+
+use crate::description::MAX_DESCRIPTION_SIZE;
+use crate::hash::SHA256_DIGEST_SIZE;
+use crate::vmsa::{
+    default_cr4, validate_control_registers, validate_xcr0, DEFAULT_CR0, DEFAULT_EFER, DEFAULT_XCR0,
+};
+
+const BUILD_EXAMPLE: &str = "Example:
+  buildigvm build --firmware OVMF.fd --output sev-snp.igvm --platform sev-snp";
+
+// The IGVM crate versions are recorded by the build script.
+const VERSION: &str = concat!(
+    env!("CARGO_PKG_VERSION"),
+    " (igvm ",
+    env!("IGVM_VERSION"),
+    ", igvm_defs ",
+    env!("IGVM_DEFS_VERSION"),
+    ")"
+);
 
 #[derive(Parser, Debug)]
+#[command(version = VERSION, after_help = BUILD_EXAMPLE)]
 pub struct CmdOptions {
-    /// Firmware file, e.g. OVMF.fd
-    #[arg(short, long)]
-    pub firmware: String,
+    #[command(subcommand)]
+    pub command: Command,
+}
+
+#[derive(Subcommand, Debug)]
+pub enum Command {
+    /// Build an IGVM file from a firmware image
+    #[command(
+        override_usage = "buildigvm build --firmware <FIRMWARE> --output <OUTPUT> --platform <PLATFORM> [OPTIONS]",
+        after_help = BUILD_EXAMPLE
+    )]
+    Build(Box<BuildOptions>),
+    /// Print the contents of an existing IGVM file
+    Dump(DumpOptions),
+    /// List the supported platforms and the IGVM platform type of each
+    Platforms,
+}
 
-    /// Output filename for the generated IGVM file
+#[derive(Args, Debug)]
+pub struct DumpOptions {
+    /// IGVM file to dump
     #[arg(short, long)]
-    pub output: String,
+    pub input: String,
+}
+
+#[derive(Args, Debug)]
+pub struct BuildOptions {
+    /// Firmware file, e.g. OVMF.fd, or '-' to read the firmware from stdin.
+    /// The OVMF metadata that describes the guest is parsed from this file
+    #[arg(short, long, required_unless_present = "raw_payload")]
+    pub firmware: Option<String>,
+
+    /// Load a flat binary at a guest physical address instead of an OVMF
+    /// firmware, specified as <HEX>=<FILE>. The BSP starts executing at the
+    /// start of the binary. Only supported for the native platform
+    #[arg(long, value_parser = parse_firmware_at, conflicts_with = "firmware")]
+    pub raw_payload: Option<(u64, String)>,
+
+    /// Place an additional firmware image at a guest physical address,
+    /// specified as <HEX>=<FILE>. No OVMF metadata is parsed from the image.
+    /// Can be specified multiple times. Also available as --blob
+    #[arg(long, visible_alias = "blob", value_parser = parse_firmware_at)]
+    pub firmware_at: Vec<(u64, String)>,
+
+    /// Guest physical address at which to place the firmware, specified in
+    /// hex. By default the firmware is placed so that it ends at 4GB
+    #[arg(long, value_parser = parse_hex_u32)]
+    pub firmware_base: Option<u32>,
+
+    /// Pad the firmware with zeros at its lowest addresses if its size is not a
+    /// multiple of the 4K page size
+    #[arg(long, default_value_t = false)]
+    pub pad_firmware: bool,
+
+    /// Guest physical address of the SEV-SNP secrets page, specified in hex.
+    /// Overrides the address defined by the firmware metadata
+    #[arg(long, value_parser = parse_hex_u32)]
+    pub secrets_gpa: Option<u32>,
+
+    /// Guest physical address of the SEV-SNP calling area page, specified in
+    /// hex. Overrides the address defined by the firmware metadata
+    #[arg(long, value_parser = parse_hex_u32)]
+    pub caa_gpa: Option<u32>,
+
+    /// Guest physical address of the SEV-SNP CPUID page, specified in hex.
+    /// Overrides the address defined by the firmware metadata
+    #[arg(long, value_parser = parse_hex_u32)]
+    pub cpuid_gpa: Option<u32>,
+
+    /// Do not populate the SEV-SNP secrets, CAA and CPUID pages defined by the
+    /// firmware, for when another component such as an SVSM provides them
+    #[arg(long, default_value_t = false)]
+    pub no_fw_metadata_pages: bool,
+
+    /// Mark the SEV-SNP calling area page as unmeasured instead of measuring
+    /// it as a zero page. The secrets page is always measured by its page type
+    #[arg(long, default_value_t = false)]
+    pub unmeasured_caa: bool,
+
+    /// Populate the 2MB aligned parts of the SEV-SNP prevalidated regions with
+    /// 2MB zero pages to reduce the number of directives. The loader must
+    /// support 2MB pages and the launch digest cannot be calculated
+    #[arg(long, default_value_t = false, conflicts_with = "measure")]
+    pub coalesce_zero_pages: bool,
+
+    /// Prevalidate an additional region of memory on SEV-SNP, for firmware
+    /// that does not define it in its metadata, specified as <HEX>:<HEX>
+    /// giving the page aligned base and size. Can be specified multiple times
+    #[arg(long, value_parser = parse_region)]
+    pub prevalidate: Vec<(u32, u32)>,
+
+    /// Mark the pages of the SEV-SNP prevalidated region with this index as
+    /// unmeasured, so the region is accepted but not included in the launch
+    /// measurement. Can be specified multiple times
+    #[arg(long)]
+    pub unmeasured_prevalidated: Vec<u32>,
+
+    /// Sort the page directives by ascending GPA, as expected by measurement
+    /// tools that reproduce the SEV-SNP launch digest from the GPA order
+    #[arg(long, default_value_t = false)]
+    pub sort_pages: bool,
+
+    /// Do not store the contents of firmware pages that are entirely zero.
+    /// The pages are omitted for the native platform and populated as zero
+    /// pages for other platforms, which changes the launch measurement
+    #[arg(long, default_value_t = false)]
+    pub sparse: bool,
+
+    /// Reserve and prevalidate a region of memory for an SVSM on SEV-SNP,
+    /// specified as <HEX>:<HEX> giving the page aligned base and size. The
+    /// region must not overlap the firmware or its SEV-SNP regions
+    #[arg(long, value_parser = parse_region)]
+    pub svsm_region: Option<(u32, u32)>,
+
+    /// Warn instead of failing when the SEV-SNP regions defined by the firmware
+    /// overlap each other or the firmware itself
+    #[arg(long, default_value_t = false)]
+    pub allow_overlap: bool,
+
+    /// Warn if the reset vector at the top of the firmware does not look like
+    /// a jump instruction, which suggests the file is not firmware
+    #[arg(long, default_value_t = false, requires = "firmware")]
+    pub check_reset_vector: bool,
+
+    /// Fail instead of warning when building for SEV-SNP with a firmware that
+    /// contains no OVMF SEV metadata
+    #[arg(long, default_value_t = false, requires = "firmware")]
+    pub require_sev_metadata: bool,
+
+    /// Fail if the SHA-256 of the firmware file, before it is decompressed,
+    /// does not match this digest, specified as 64 hex digits
+    #[arg(long, value_parser = parse_sha256, requires = "firmware")]
+    pub expect_firmware_sha256: Option<[u8; SHA256_DIGEST_SIZE]>,
+
+    /// Output filename for the generated IGVM file, or '-' to write to stdout
+    #[arg(short, long, required_unless_present_any = ["check", "output_dir"])]
+    pub output: Option<String>,
+
+    /// Write a separate IGVM file for each platform into this directory,
+    /// named after the platform, e.g. sev-snp.igvm
+    #[arg(long, conflicts_with = "output")]
+    pub output_dir: Option<String>,
 
-    #[arg(short, long, default_value_t = 1)]
+    /// Add the platforms being built to an existing IGVM file, which is read
+    /// from this file and merged with the new platforms before it is written
+    /// to the output. The existing file must not already support any of the
+    /// platforms
+    #[arg(long, conflicts_with = "output_dir")]
+    pub append_platform: Option<String>,
+
+    /// A free-form description, such as a build ID or source commit, to embed
+    /// in the IGVM file. It is shown by the dump command
+    #[arg(long, value_parser = parse_description)]
+    pub description: Option<String>,
+
+    /// Permissions of the output files, specified in octal, e.g. 644. Only
+    /// supported on Unix
+    #[arg(long, value_parser = parse_mode)]
+    pub mode: Option<u32>,
+
+    /// Parse the serialized IGVM file back and check that it contains the
+    /// headers that were built before it is written
+    #[arg(long, default_value_t = false)]
+    pub self_check: bool,
+
+    /// Write a JSON manifest describing the inputs and configuration of the
+    /// build to this file. Not written with --check
+    #[arg(long)]
+    pub manifest: Option<String>,
+
+    /// Parse the inputs and build the IGVM file, printing a summary instead of
+    /// writing the output file
+    #[arg(long, default_value_t = false)]
+    pub check: bool,
+
+    #[arg(short, long, default_value_t = 1, value_parser = clap::value_parser!(u16).range(1..))]
     pub cpucount: u16,
 
+    /// The maximum CPU count that will be accepted
+    #[arg(long, default_value_t = 1024)]
+    pub max_cpucount: u16,
+
+    /// Size in MB of the guest RAM, starting at address zero, that the guest
+    /// requires to be present
+    #[arg(long)]
+    pub memory_size: Option<u32>,
+
+    /// Kernel image file, e.g. bzImage, to load into guest memory
+    #[arg(long)]
+    pub kernel: Option<String>,
+
+    /// Guest physical address at which to load the kernel, specified in hex
+    #[arg(long, value_parser = parse_hex_u64, default_value = "0x1000000")]
+    pub kernel_gpa: u64,
+
+    /// Initial ramdisk file to load into guest memory
+    #[arg(long, requires = "kernel")]
+    pub initrd: Option<String>,
+
+    /// Guest physical address at which to load the initrd, specified in hex
+    #[arg(long, value_parser = parse_hex_u64, default_value = "0x4000000")]
+    pub initrd_gpa: u64,
+
+    /// Guest physical address of the VMSA pages, specified in hex. The default
+    /// is outside guest RAM and must not overlap any populated page
+    #[arg(long, value_parser = parse_hex_u64, default_value = "0xFFFFFFFFF000")]
+    pub vmsa_gpa: u64,
+
+    /// Do not generate VMSAs for the SEV-ES and SEV-SNP platforms, producing a
+    /// firmware-only image for VMMs that initialize the vCPU state themselves
+    #[arg(
+        long,
+        default_value_t = false,
+        conflicts_with_all = ["vmsa_gpa", "ap_reset", "bsp_reg", "gdtr", "idtr", "long_mode_bsp", "cr0", "cr4", "efer", "xcr0"]
+    )]
+    pub no_vmsa: bool,
+
+    /// Override the guest physical address at which the BSP begins execution,
+    /// specified in hex. Defaults to the reset vector at 0xFFFFFFF0
+    #[arg(long, value_parser = parse_hex_u32)]
+    pub bsp_reset: Option<u32>,
+
+    /// Override the guest physical address at which the APs begin execution
+    /// on SEV-ES and SEV-SNP, specified in hex. Required when the CPU count is
+    /// greater than one and the firmware has no SEV-ES reset block
+    #[arg(long, value_parser = parse_hex_u32)]
+    pub ap_reset: Option<u32>,
+
+    /// Set the base and limit of the initial GDT in the BSP VMSA, specified
+    /// as <HEX>:<HEX>
+    #[arg(long, value_parser = parse_descriptor_table)]
+    pub gdtr: Option<(u64, u32)>,
+
+    /// Set the base and limit of the initial IDT in the BSP VMSA, specified
+    /// as <HEX>:<HEX>
+    #[arg(long, value_parser = parse_descriptor_table)]
+    pub idtr: Option<(u64, u32)>,
+
+    /// Start the BSP in 64-bit long mode on SEV-ES and SEV-SNP, with paging
+    /// enabled using the page tables at the given guest physical address,
+    /// specified in hex. CS and SS are set to flat 64-bit code and data
+    /// segments and the BSP starts executing at the reset address
+    #[arg(long, value_parser = parse_hex_u64)]
+    pub long_mode_bsp: Option<u64>,
+
+    /// Override the initial value of CR0 in the VMSA of each processor,
+    /// specified in hex
+    #[arg(long, value_parser = parse_hex_u64)]
+    pub cr0: Option<u64>,
+
+    /// Override the initial value of CR4 in the VMSA of each processor,
+    /// specified in hex
+    #[arg(long, value_parser = parse_hex_u64)]
+    pub cr4: Option<u64>,
+
+    /// Override the initial value of EFER in the VMSA of each processor,
+    /// specified in hex
+    #[arg(long, value_parser = parse_hex_u64)]
+    pub efer: Option<u64>,
+
+    /// Override the initial value of XCR0 in the VMSA of each processor,
+    /// specified in hex. The x87 bit must be set. CR4.OSXSAVE is set by
+    /// default when XCR0 enables any other state
+    #[arg(long, value_parser = parse_hex_u64)]
+    pub xcr0: Option<u64>,
+
+    /// Command line to pass to the guest in a parameter area
+    #[arg(long)]
+    pub cmdline: Option<String>,
+
+    /// Guest physical address at which the command line parameter area is
+    /// inserted, specified in hex
+    #[arg(long, value_parser = parse_hex_u64, default_value = "0x20000")]
+    pub cmdline_gpa: u64,
+
+    /// Guest physical address at which to insert a memory map describing the
+    /// firmware prevalidated regions, specified in hex
+    #[arg(long, value_parser = parse_hex_u64)]
+    pub memory_map_gpa: Option<u64>,
+
+    /// Guest physical address at which to insert a parameter area containing
+    /// the number of virtual processors as a 32-bit value, specified in hex.
+    /// The area initially contains the value of --cpucount
+    #[arg(long, value_parser = parse_hex_u64)]
+    pub vp_count_gpa: Option<u64>,
+
+    /// Directory of ACPI table files to concatenate, in filename order, into a
+    /// parameter area that is inserted at --acpi-gpa
+    #[arg(long, requires = "acpi_gpa")]
+    pub acpi: Option<String>,
+
+    /// Guest physical address at which to insert the ACPI tables, specified in
+    /// hex. The OVMF metadata does not describe a location for the tables so
+    /// this must match the address the firmware expects
+    #[arg(long, value_parser = parse_hex_u64, requires = "acpi")]
+    pub acpi_gpa: Option<u64>,
+
+    /// Set the initial value of a general purpose register in the BSP VMSA,
+    /// specified as <NAME>=<HEX>, e.g. rdi=0x7000. Can be specified multiple
+    /// times
+    #[arg(long, value_parser = parse_register)]
+    pub bsp_reg: Vec<(String, u64)>,
+
+    /// Initial stack pointer of the BSP, specified in hex. Should be 16-byte
+    /// aligned. Defaults to zero
+    #[arg(long, value_parser = parse_hex_u64)]
+    pub bsp_rsp: Option<u64>,
+
+    /// The guest physical address boundary above which memory is shared with
+    /// the host, specified in hex. Must be a power of two. Not used for the
+    /// native platform
+    #[arg(long, value_parser = parse_hex_u64)]
+    pub shared_gpa_boundary: Option<u64>,
+
+    /// The highest VTL advertised for the SEV-SNP and TDX platforms. The IGVM
+    /// format only supports VTL0 and VTL2
+    #[arg(long, default_value_t = 0, value_parser = parse_vtl)]
+    pub highest_vtl: u8,
+
+    /// The platform version advertised in the supported platform header of
+    /// each platform. It must be a version that is defined for every platform
+    #[arg(long, default_value_t = 1)]
+    pub platform_version: u16,
+
+    /// The compatibility mask bits to assign to the platforms, specified in
+    /// hex. The mask needs one bit per platform, which are assigned in the
+    /// order the platforms are listed by the platforms command
+    #[arg(long, value_parser = parse_hex_u32)]
+    pub compat_mask: Option<u32>,
+
+    /// The revision of the IGVM file format to generate
+    #[arg(long, default_value_t = 1, value_parser = clap::value_parser!(u8).range(1..=2))]
+    pub revision: u8,
+
+    /// Calculate and print the expected SEV-SNP launch digest of the IGVM file
+    #[arg(long, default_value_t = false)]
+    pub measure: bool,
+
+    /// The VMPL at which the firmware runs on SEV-SNP. A value other than zero
+    /// requires a VMPL0 component, such as an SVSM, to grant the firmware
+    /// access to its pages
+    #[arg(long, default_value_t = 0, value_parser = clap::value_parser!(u8).range(0..=3))]
+    pub vmpl: u8,
+
+    /// Enable an SEV feature in the VMSA of each virtual processor. Can be
+    /// specified multiple times
+    #[arg(long, value_enum)]
+    pub sev_feature: Vec<SevFeature>,
+
+    /// Override the default guest policy for the platform, specified in hex.
+    /// Applies to the single SEV, SEV-ES or SEV-SNP platform being built;
+    /// other platforms use their default policy
+    #[arg(long, value_parser = parse_hex_u64)]
+    pub policy: Option<u64>,
+
+    /// SEV-SNP CPUID page file, containing the CPUID table to populate into
+    /// the firmware CPUID page
+    #[arg(long)]
+    pub cpuid_page: Option<String>,
+
+    /// SEV-SNP secrets page file, containing up to a page of data to place in
+    /// the secrets page. Intended for testing only: the SEV-SNP firmware fills
+    /// the secrets page so real deployments leave it empty
+    #[arg(long)]
+    pub secrets_data: Option<String>,
+
+    /// SEV-SNP ID block file, containing the ID block structure defined in the
+    /// SEV-SNP firmware ABI specification
+    #[arg(long, requires = "id_auth")]
+    pub id_block: Option<String>,
+
+    /// SEV-SNP ID authentication information file, containing the signature of
+    /// the ID block, the ID key and optionally the author key
+    #[arg(long, requires = "id_block")]
+    pub id_auth: Option<String>,
+
+    /// Signature algorithm of the ID key and author key in the SEV-SNP ID
+    /// authentication information
+    #[arg(long, value_enum, default_value_t = IdKeyAlgorithm::EcdsaP384)]
+    pub id_key_algorithm: IdKeyAlgorithm,
+
     /// Print verbose output
     #[arg(short, long, default_value_t = false)]
     pub verbose: bool,
 
-    #[arg(value_enum)]
-    pub platform: Platform,
+    /// Do not print warnings or any other output that is not an error
+    #[arg(short, long, default_value_t = false, conflicts_with_all = ["verbose", "progress"])]
+    pub quiet: bool,
+
+    /// Print progress to stderr as the page directives and VMSAs are generated
+    /// and the IGVM file is serialized
+    #[arg(long, default_value_t = false)]
+    pub progress: bool,
+
+    /// Format of the verbose output. JSON output is written to stderr
+    #[arg(long, value_enum, default_value_t = OutputFormat::Text)]
+    pub format: OutputFormat,
+
+    /// Platform to build the IGVM file for. Can be specified multiple times to
+    /// generate a single IGVM file that supports several platforms
+    #[arg(short, long, value_enum, ignore_case = true, required = true)]
+    pub platform: Vec<Platform>,
+}
+
+impl BuildOptions {
+    /// Print a warning to stderr unless the output is quiet.
+    pub fn warn(&self, message: &str) {
+        if !self.quiet {
+            eprintln!("Warning: {message}");
+        }
+    }
+
+    pub fn output_to_stdout(&self) -> bool {
+        self.output.as_deref() == Some("-")
+    }
+
+    /// Check the constraints between options that clap cannot express, such
+    /// as options that only apply to some platforms. All problems are
+    /// reported together and no input files are read.
+    pub fn validate(&self) -> Result<(), Box<dyn Error>> {
+        let has_snp = self.platform.contains(&Platform::SevSnp);
+        let has_sev_es_or_snp = self
+            .platform
+            .iter()
+            .any(|platform| matches!(platform, Platform::SevEs | Platform::SevSnp));
+        let mut errors = Vec::<String>::new();
+
+        if cfg!(not(feature = "json"))
+            && (self.manifest.is_some() || self.format == OutputFormat::Json)
+        {
+            errors.push(
+                "JSON output is not supported: buildigvm was built without the json feature"
+                    .to_string(),
+            );
+        }
+        if self.cpucount > self.max_cpucount {
+            errors.push(format!(
+                "The CPU count {} exceeds the maximum of {}. Use --max-cpucount to increase the limit",
+                self.cpucount, self.max_cpucount
+            ));
+        }
+        if self.cpuid_page.is_some() && !has_snp {
+            errors.push("A CPUID page can only be used with the SEV-SNP platform".to_string());
+        }
+        if self.secrets_data.is_some() && !has_snp {
+            errors.push("Secrets data can only be used with the SEV-SNP platform".to_string());
+        }
+        if self.policy.is_some() {
+            // Each SEV generation has its own policy format, so a single
+            // policy only makes sense when exactly one of them is selected.
+            let sev_platforms = self
+                .platform
+                .iter()
+                .filter(|platform| {
+                    matches!(platform, Platform::Sev | Platform::SevEs | Platform::SevSnp)
+                })
+                .count();
+            match sev_platforms {
+                0 => errors.push(
+                    "A guest policy can only be used with the SEV, SEV-ES or SEV-SNP platform"
+                        .to_string(),
+                ),
+                1 => (),
+                _ => errors.push(
+                    "A guest policy cannot be used when more than one of the SEV, SEV-ES and SEV-SNP platforms is selected"
+                        .to_string(),
+                ),
+            }
+        }
+        if self
+            .platform
+            .iter()
+            .all(|platform| *platform == Platform::Tdx)
+            && (!self.bsp_reg.is_empty() || self.bsp_reset.is_some() || self.bsp_rsp.is_some())
+        {
+            errors
+                .push("The initial BSP state cannot be configured on the TDX platform".to_string());
+        }
+        if let Some(boundary) = self.shared_gpa_boundary {
+            if !boundary.is_power_of_two() {
+                errors.push(format!(
+                    "The shared GPA boundary {boundary:#X} is not a power of two"
+                ));
+            }
+            if self
+                .platform
+                .iter()
+                .all(|platform| *platform == Platform::Native)
+            {
+                errors.push(
+                    "A shared GPA boundary cannot be used with the native platform".to_string(),
+                );
+            }
+        }
+        if self.no_fw_metadata_pages {
+            if !has_snp {
+                errors.push(
+                    "The firmware metadata pages can only be skipped for the SEV-SNP platform"
+                        .to_string(),
+                );
+            }
+            if self.cpuid_page.is_some() {
+                errors.push(
+                    "A CPUID page file cannot be used when the firmware metadata pages are skipped"
+                        .to_string(),
+                );
+            }
+            if self.secrets_data.is_some() {
+                errors.push(
+                    "Secrets data cannot be used when the firmware metadata pages are skipped"
+                        .to_string(),
+                );
+            }
+        }
+        for platform in &self.platform {
+            let version = platform.platform_version();
+            if self.platform_version != version {
+                errors.push(format!(
+                    "Platform version {} is not supported by the {platform:?} platform, which only supports version {version}",
+                    self.platform_version
+                ));
+            }
+        }
+        if let Some(compat_mask) = self.compat_mask {
+            let mut platforms = self.platform.clone();
+            platforms.sort();
+            platforms.dedup();
+            if compat_mask.count_ones() as usize != platforms.len() {
+                errors.push(format!(
+                    "The compatibility mask {compat_mask:#X} must have exactly {} bits set, one for each platform",
+                    platforms.len()
+                ));
+            }
+        }
+        if self.highest_vtl != 0
+            && !self
+                .platform
+                .iter()
+                .any(|platform| matches!(platform, Platform::SevSnp | Platform::Tdx))
+        {
+            errors.push("VTL2 can only be used with the SEV-SNP and TDX platforms".to_string());
+        }
+        if self.bsp_rsp.is_some() && self.bsp_reg.iter().any(|(name, _)| name == "rsp") {
+            errors.push("The BSP RSP cannot be set by both --bsp-rsp and --bsp-reg".to_string());
+        }
+        if self.raw_payload.is_some() {
+            if self
+                .platform
+                .iter()
+                .any(|platform| *platform != Platform::Native)
+            {
+                errors.push("A raw payload can only be used with the native platform".to_string());
+            }
+            if self.pad_firmware || self.firmware_base.is_some() {
+                errors.push(
+                    "The firmware padding and base cannot be used with a raw payload".to_string(),
+                );
+            }
+        }
+        if self.unmeasured_caa && !has_snp {
+            errors.push(
+                "The calling area page can only be unmeasured for the SEV-SNP platform".to_string(),
+            );
+        }
+        if !self.prevalidate.is_empty() && !has_snp {
+            errors.push("Regions can only be prevalidated for the SEV-SNP platform".to_string());
+        }
+        if !self.unmeasured_prevalidated.is_empty() && !has_snp {
+            errors.push(
+                "Prevalidated regions can only be unmeasured for the SEV-SNP platform".to_string(),
+            );
+        }
+        if self.svsm_region.is_some() && !has_snp {
+            errors.push("An SVSM region can only be reserved for the SEV-SNP platform".to_string());
+        }
+        if self.coalesce_zero_pages && !has_snp {
+            errors.push("Zero pages can only be coalesced for the SEV-SNP platform".to_string());
+        }
+        if self.measure && !has_snp {
+            errors.push(
+                "A launch digest can only be calculated for the SEV-SNP platform".to_string(),
+            );
+        }
+        if (self.secrets_gpa.is_some() || self.caa_gpa.is_some() || self.cpuid_gpa.is_some())
+            && !has_snp
+        {
+            errors.push(
+                "The secrets, CAA and CPUID pages can only be placed for the SEV-SNP platform"
+                    .to_string(),
+            );
+        }
+        for (name, gpa) in [
+            ("secrets", self.secrets_gpa),
+            ("calling area", self.caa_gpa),
+            ("CPUID", self.cpuid_gpa),
+        ] {
+            if let Some(gpa) = gpa.filter(|gpa| *gpa as u64 & (PAGE_SIZE_4K - 1) != 0) {
+                errors.push(format!(
+                    "The SEV-SNP {name} page at {gpa:#X} is not 4K aligned"
+                ));
+            }
+        }
+        if self.mode.is_some() && !cfg!(unix) {
+            errors.push("The output file mode can only be set on Unix".to_string());
+        }
+        if self.no_vmsa && !has_sev_es_or_snp {
+            errors
+                .push("VMSAs are only generated for the SEV-ES and SEV-SNP platforms".to_string());
+        }
+        if self.ap_reset.is_some() && !has_sev_es_or_snp {
+            errors.push(
+                "The AP reset address can only be set for the SEV-ES and SEV-SNP platforms"
+                    .to_string(),
+            );
+        }
+        if (self.gdtr.is_some() || self.idtr.is_some()) && !has_sev_es_or_snp {
+            errors.push(
+                "The GDT and IDT can only be set for the SEV-ES and SEV-SNP platforms".to_string(),
+            );
+        }
+        if let Some(cr3) = self.long_mode_bsp {
+            if !has_sev_es_or_snp {
+                errors.push(
+                    "A long mode BSP can only be used with the SEV-ES and SEV-SNP platforms"
+                        .to_string(),
+                );
+            }
+            if cr3 & (PAGE_SIZE_4K - 1) != 0 {
+                errors.push(format!(
+                    "The long mode page table root {cr3:#X} is not page aligned"
+                ));
+            }
+        }
+        if let Some(xcr0) = self.xcr0 {
+            if let Err(e) = validate_xcr0(xcr0) {
+                errors.push(e.to_string());
+            }
+        }
+        if self.cr0.is_some() || self.cr4.is_some() || self.efer.is_some() || self.xcr0.is_some() {
+            if !has_sev_es_or_snp {
+                errors.push(
+                    "Control registers can only be set for the SEV-ES and SEV-SNP platforms"
+                        .to_string(),
+                );
+            }
+            let xcr0 = self.xcr0.unwrap_or(DEFAULT_XCR0);
+            if let Err(e) = validate_control_registers(
+                self.cr0.unwrap_or(DEFAULT_CR0),
+                self.cr4.unwrap_or(default_cr4(xcr0)),
+                self.efer.unwrap_or(DEFAULT_EFER),
+                xcr0,
+            ) {
+                errors.push(e.to_string());
+            }
+        }
+        if self.vmpl != 0 && !has_snp {
+            errors.push("A VMPL can only be specified for the SEV-SNP platform".to_string());
+        }
+        if !self.sev_feature.is_empty() {
+            if !has_sev_es_or_snp {
+                errors.push(
+                    "SEV features can only be used with the SEV-ES and SEV-SNP platforms"
+                        .to_string(),
+                );
+            } else if !has_snp {
+                if let Some(feature) = self.sev_feature.iter().find(|feature| feature.snp_only()) {
+                    errors.push(format!(
+                        "The SEV feature {feature:?} can only be used with the SEV-SNP platform"
+                    ));
+                }
+            }
+        }
+        if let Some(gpa) = self.acpi_gpa {
+            if gpa & (PAGE_SIZE_4K - 1) != 0 {
+                errors.push(format!("The ACPI table GPA {gpa:#X} is not page aligned"));
+            }
+        }
+        if self.id_block.is_some() && self.id_auth.is_some() && !has_snp {
+            errors.push("An ID block can only be used with the SEV-SNP platform".to_string());
+        }
+
+        if errors.is_empty() {
+            Ok(())
+        } else {
+            Err(errors.join("\n").into())
+        }
+    }
+}
+
+#[derive(Copy, Clone, PartialEq, Eq, ValueEnum, Debug)]
+pub enum SevFeature {
+    /// Reflect #VC exceptions to the hypervisor (SEV-SNP only)
+    ReflectVc,
+    /// Restricted injection (SEV-SNP only)
+    RestrictInjection,
+    /// Alternate injection (SEV-SNP only)
+    AlternateInjection,
+    /// Debug register swap
+    DebugSwap,
+    /// Prevent the host from using IBS
+    PreventHostIbs,
+    /// Branch target buffer isolation (SEV-SNP only)
+    SnpBtbIsolation,
+    /// Secure TSC (SEV-SNP only)
+    SecureTsc,
+    /// VMSA register protection (SEV-SNP only)
+    VmsaRegProtection,
+}
+
+impl SevFeature {
+    pub fn snp_only(&self) -> bool {
+        !matches!(self, SevFeature::DebugSwap | SevFeature::PreventHostIbs)
+    }
+}
+
+#[derive(Copy, Clone, PartialEq, Eq, ValueEnum, Debug)]
+pub enum IdKeyAlgorithm {
+    /// ECDSA P-384 with SHA-384
+    EcdsaP384,
+}
+
+#[derive(Copy, Clone, PartialEq, Eq, ValueEnum, Debug)]
+pub enum OutputFormat {
+    /// Human readable text
+    Text,
+    /// Machine readable JSON
+    Json,
 }
 
 #[derive(Copy, Clone, PartialEq, Eq, PartialOrd, Ord, ValueEnum, Debug)]
@@ -37,4 +774,222 @@ pub enum Platform {
     SevSnp,
     /// An X86-64 platform that does not include support for any isolation technology
     Native,
+    /// Intel TDX
+    Tdx,
+}
+
+impl Platform {
+    /// The IGVM platform type that the platform is built for.
+    pub fn platform_type(&self) -> IgvmPlatformType {
+        match self {
+            Platform::Sev => IgvmPlatformType::SEV,
+            Platform::SevEs => IgvmPlatformType::SEV_ES,
+            Platform::SevSnp => IgvmPlatformType::SEV_SNP,
+            Platform::Native => IgvmPlatformType::NATIVE,
+            Platform::Tdx => IgvmPlatformType::TDX,
+        }
+    }
+
+    /// The IGVM format defines a single version of each platform.
+    pub fn platform_version(&self) -> u16 {
+        match self {
+            Platform::Sev => IGVM_SEV_PLATFORM_VERSION,
+            Platform::SevEs => IGVM_SEV_ES_PLATFORM_VERSION,
+            Platform::SevSnp => IGVM_SEV_SNP_PLATFORM_VERSION,
+            Platform::Native => IGVM_NATIVE_PLATFORM_VERSION,
+            Platform::Tdx => IGVM_TDX_PLATFORM_VERSION,
+        }
+    }
+}
+
+/// Print each platform that can be passed to the build command along with its
+/// description and IGVM platform type.
+pub fn list_platforms() {
+    for platform in Platform::value_variants() {
+        if let Some(value) = platform.to_possible_value() {
+            let platform_type = format!("{:?}", platform.platform_type());
+            let help = value.get_help().map(|help| help.to_string());
+            println!(
+                "{:<8} {platform_type:<8} {}",
+                value.get_name(),
+                help.unwrap_or_default()
+            );
+        }
+    }
+}
+
+fn parse_hex_u64(value: &str) -> Result<u64, String> {
+    let digits = value
+        .strip_prefix("0x")
+        .or_else(|| value.strip_prefix("0X"))
+        .unwrap_or(value);
+    u64::from_str_radix(digits, 16).map_err(|e| format!("invalid hex value '{value}': {e}"))
+}
+
+fn parse_hex_u32(value: &str) -> Result<u32, String> {
+    u32::try_from(parse_hex_u64(value)?).map_err(|e| format!("invalid hex value '{value}': {e}"))
+}
+
+fn parse_description(value: &str) -> Result<String, String> {
+    if value.len() > MAX_DESCRIPTION_SIZE {
+        return Err(format!(
+            "the description is {} bytes, which is more than the maximum of {MAX_DESCRIPTION_SIZE}",
+            value.len()
+        ));
+    }
+    Ok(value.to_string())
+}
+
+fn parse_mode(value: &str) -> Result<u32, String> {
+    let mode = u32::from_str_radix(value.trim_start_matches("0o"), 8)
+        .map_err(|e| format!("invalid file mode '{value}': {e}"))?;
+    if mode > 0o7777 {
+        return Err(format!("invalid file mode '{value}': must be at most 7777"));
+    }
+    Ok(mode)
+}
+
+fn parse_sha256(value: &str) -> Result<[u8; SHA256_DIGEST_SIZE], String> {
+    if value.len() != SHA256_DIGEST_SIZE * 2 || !value.is_ascii() {
+        return Err(format!(
+            "invalid SHA-256 digest '{value}': expected {} hex digits",
+            SHA256_DIGEST_SIZE * 2
+        ));
+    }
+    let mut digest = [0u8; SHA256_DIGEST_SIZE];
+    for (i, byte) in digest.iter_mut().enumerate() {
+        *byte = u8::from_str_radix(&value[i * 2..i * 2 + 2], 16)
+            .map_err(|e| format!("invalid SHA-256 digest '{value}': {e}"))?;
+    }
+    Ok(digest)
+}
+
+fn parse_descriptor_table(value: &str) -> Result<(u64, u32), String> {
+    let (base, limit) = value.split_once(':').ok_or(format!(
+        "invalid descriptor table '{value}': expected <HEX>:<HEX>"
+    ))?;
+    let limit = parse_hex_u32(limit.trim())?;
+    if limit == 0 || limit > 0xffff {
+        return Err(format!(
+            "invalid descriptor table '{value}': the limit must be between 0x1 and 0xFFFF"
+        ));
+    }
+    Ok((parse_hex_u64(base.trim())?, limit))
+}
+
+fn parse_region(value: &str) -> Result<(u32, u32), String> {
+    let (base, size) = value
+        .split_once(':')
+        .ok_or(format!("invalid region '{value}': expected <HEX>:<HEX>"))?;
+    let base = parse_hex_u32(base.trim())?;
+    let size = parse_hex_u32(size.trim())?;
+    if size == 0 {
+        return Err(format!(
+            "invalid region '{value}': the size must not be zero"
+        ));
+    }
+    if (base | size) as u64 & (PAGE_SIZE_4K - 1) != 0 {
+        return Err(format!(
+            "invalid region '{value}': the base and size must be page aligned"
+        ));
+    }
+    if base as u64 + size as u64 > 0x1_0000_0000 {
+        return Err(format!(
+            "invalid region '{value}': the region extends beyond 4GB"
+        ));
+    }
+    Ok((base, size))
+}
+
+fn parse_vtl(value: &str) -> Result<u8, String> {
+    match value.parse::<u8>() {
+        Ok(vtl @ (0 | 2)) => Ok(vtl),
+        Ok(1) => Err(
+            "invalid VTL '1': the IGVM format does not support VTL1 as the highest VTL".to_string(),
+        ),
+        _ => Err(format!("invalid VTL '{value}': must be 0 or 2")),
+    }
+}
+
+fn parse_firmware_at(value: &str) -> Result<(u64, String), String> {
+    let (gpa, filename) = value.split_once('=').ok_or(format!(
+        "invalid firmware image '{value}': expected <HEX>=<FILE>"
+    ))?;
+    if filename.is_empty() {
+        return Err(format!("invalid firmware image '{value}': no file given"));
+    }
+    Ok((parse_hex_u64(gpa.trim())?, filename.to_string()))
+}
+
+fn parse_register(value: &str) -> Result<(String, u64), String> {
+    let (name, register_value) = value
+        .split_once('=')
+        .ok_or(format!("invalid register '{value}': expected <NAME>=<HEX>"))?;
+    Ok((
+        name.trim().to_lowercase(),
+        parse_hex_u64(register_value.trim())?,
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn descriptor_table_limits() {
+        assert_eq!(parse_descriptor_table("7000:27"), Ok((0x7000, 0x27)));
+        assert_eq!(
+            parse_descriptor_table("0x7000:0xffff"),
+            Ok((0x7000, 0xffff))
+        );
+        assert!(parse_descriptor_table("7000:0").is_err());
+        assert!(parse_descriptor_table("7000:10000").is_err());
+        assert!(parse_descriptor_table("7000").is_err());
+    }
+
+    fn build_options(args: &[&str]) -> BuildOptions {
+        let argv = [
+            &["buildigvm", "build", "-f", "OVMF.fd", "-o", "out.igvm"],
+            args,
+        ]
+        .concat();
+        match CmdOptions::try_parse_from(argv).unwrap().command {
+            Command::Build(options) => *options,
+            _ => unreachable!(),
+        }
+    }
+
+    #[test]
+    fn policy_needs_one_sev_platform() {
+        for args in [
+            &["-p", "sev-snp", "--policy", "30000"][..],
+            &["-p", "sev-snp", "-p", "native", "--policy", "30000"],
+        ] {
+            assert!(build_options(args).validate().is_ok(), "{args:?}");
+        }
+        for args in [
+            &["-p", "native", "--policy", "0"][..],
+            &["-p", "tdx", "--policy", "0"],
+            &["-p", "sev", "-p", "sev-es", "--policy", "5"],
+        ] {
+            assert!(build_options(args).validate().is_err(), "{args:?}");
+        }
+    }
+
+    #[test]
+    fn platform_version_must_be_defined() {
+        assert_eq!(build_options(&["-p", "sev-snp"]).platform_version, 1);
+        assert!(build_options(&["-p", "sev-snp", "--platform-version", "1"])
+            .validate()
+            .is_ok());
+        let err = build_options(&["-p", "native", "-p", "tdx", "--platform-version", "2"])
+            .validate()
+            .unwrap_err()
+            .to_string();
+        assert!(
+            err.contains("not supported by the Native platform"),
+            "{err}"
+        );
+        assert!(err.contains("not supported by the Tdx platform"), "{err}");
+    }
 }
@@ -0,0 +1,121 @@
+// SPDX-License-Identifier: MIT OR Apache-2.0
+//
+// Copyright (c) 2024 SUSE LLC
+//
+// Author: Roy Hopkins <roy.hopkins@suse.com>
+
+use std::error::Error;
+#[cfg(feature = "compression")]
+use std::io::Read;
+
+const GZIP_MAGIC: [u8; 2] = [0x1f, 0x8b];
+const ZSTD_MAGIC: [u8; 4] = [0x28, 0xb5, 0x2f, 0xfd];
+
+#[cfg(feature = "compression")]
+fn decompress_gzip(data: &[u8], filename: &str, limit: usize) -> Result<Vec<u8>, Box<dyn Error>> {
+    let mut decompressed = Vec::new();
+    flate2::read::MultiGzDecoder::new(data)
+        .take((limit as u64).saturating_add(1))
+        .read_to_end(&mut decompressed)
+        .inspect_err(|_| {
+            eprintln!("Failed to decompress gzip file {filename}");
+        })?;
+    Ok(decompressed)
+}
+
+#[cfg(feature = "compression")]
+fn decompress_zstd(data: &[u8], filename: &str, limit: usize) -> Result<Vec<u8>, Box<dyn Error>> {
+    let mut decompressed = Vec::new();
+    zstd::stream::read::Decoder::new(data)?
+        .take((limit as u64).saturating_add(1))
+        .read_to_end(&mut decompressed)
+        .inspect_err(|_| {
+            eprintln!("Failed to decompress zstd file {filename}");
+        })?;
+    Ok(decompressed)
+}
+
+#[cfg(not(feature = "compression"))]
+fn decompress_gzip(_data: &[u8], filename: &str, _limit: usize) -> Result<Vec<u8>, Box<dyn Error>> {
+    Err(format!(
+        "{filename} is compressed with gzip but buildigvm was built without the compression feature"
+    )
+    .into())
+}
+
+#[cfg(not(feature = "compression"))]
+fn decompress_zstd(_data: &[u8], filename: &str, _limit: usize) -> Result<Vec<u8>, Box<dyn Error>> {
+    Err(format!(
+        "{filename} is compressed with zstd but buildigvm was built without the compression feature"
+    )
+    .into())
+}
+
+/// Decompress the contents of a file if it starts with the magic bytes of a
+/// supported compression format, otherwise return the data unchanged. At most
+/// one byte more than `limit` is decompressed, so the caller can reject data
+/// that is too large without decompressing all of it.
+pub fn decompress(data: Vec<u8>, filename: &str, limit: usize) -> Result<Vec<u8>, Box<dyn Error>> {
+    if data.starts_with(&GZIP_MAGIC) {
+        decompress_gzip(&data, filename, limit)
+    } else if data.starts_with(&ZSTD_MAGIC) {
+        decompress_zstd(&data, filename, limit)
+    } else {
+        Ok(data)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn uncompressed_data_is_unchanged() {
+        let data = b"not compressed".to_vec();
+        assert_eq!(decompress(data.clone(), "test", usize::MAX).unwrap(), data);
+    }
+
+    #[cfg(feature = "compression")]
+    #[test]
+    fn gzip_and_zstd_are_decompressed() {
+        use std::io::Write;
+
+        let data = vec![0x5a; 0x3000];
+        let mut encoder = flate2::write::GzEncoder::new(Vec::new(), flate2::Compression::default());
+        encoder.write_all(&data).unwrap();
+        let gzip = encoder.finish().unwrap();
+        assert_eq!(decompress(gzip, "test.gz", 0x3000).unwrap(), data);
+
+        let zstd = zstd::stream::encode_all(data.as_slice(), 0).unwrap();
+        assert_eq!(decompress(zstd, "test.zst", 0x3000).unwrap(), data);
+    }
+
+    #[cfg(feature = "compression")]
+    #[test]
+    fn output_is_limited() {
+        use std::io::Write;
+
+        // 16MB of zeros compresses to a few KB but only one byte more than
+        // the limit is decompressed.
+        let data = vec![0; 0x1000000];
+        let mut encoder = flate2::write::GzEncoder::new(Vec::new(), flate2::Compression::best());
+        encoder.write_all(&data).unwrap();
+        let gzip = encoder.finish().unwrap();
+        assert!(gzip.len() < 0x10000);
+        assert_eq!(decompress(gzip, "bomb.gz", 0x1000).unwrap().len(), 0x1001);
+
+        let zstd = zstd::stream::encode_all(data.as_slice(), 19).unwrap();
+        assert!(zstd.len() < 0x10000);
+        assert_eq!(decompress(zstd, "bomb.zst", 0x1000).unwrap().len(), 0x1001);
+    }
+
+    #[test]
+    fn corrupt_input_is_rejected() {
+        let mut data = GZIP_MAGIC.to_vec();
+        data.extend_from_slice(&[0xff; 16]);
+        assert!(decompress(data, "bad.gz", usize::MAX).is_err());
+        let mut data = ZSTD_MAGIC.to_vec();
+        data.extend_from_slice(&[0xff; 16]);
+        assert!(decompress(data, "bad.zst", usize::MAX).is_err());
+    }
+}
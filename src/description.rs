@@ -0,0 +1,41 @@
+// SPDX-License-Identifier: MIT OR Apache-2.0
+//
+// Copyright (c) 2024 SUSE LLC
+//
+// Author: Roy Hopkins <roy.hopkins@suse.com>
+
+use igvm::IgvmDirectiveHeader;
+use igvm_defs::PAGE_SIZE_4K;
+
+// IGVM has no directive for free-form metadata, so the description is carried
+// as the initial data of a parameter area that is never inserted into guest
+// memory. The tag identifies the area when the file is read back.
+const DESCRIPTION_TAG: &[u8] = b"buildigvm-description:";
+
+/// The longest description, in bytes, that fits in a single page parameter
+/// area after the tag.
+pub const MAX_DESCRIPTION_SIZE: usize = PAGE_SIZE_4K as usize - DESCRIPTION_TAG.len();
+
+/// Build the parameter area that carries the description. The area is not
+/// inserted so it does not affect the guest or the launch measurement.
+pub fn description_area(description: &str, parameter_area_index: u32) -> IgvmDirectiveHeader {
+    let mut initial_data = DESCRIPTION_TAG.to_vec();
+    initial_data.extend_from_slice(description.as_bytes());
+    IgvmDirectiveHeader::ParameterArea {
+        number_of_bytes: PAGE_SIZE_4K,
+        parameter_area_index,
+        initial_data,
+    }
+}
+
+/// Get the description from the initial data of a parameter area, if it is a
+/// description area.
+pub fn parse_description(initial_data: &[u8]) -> Option<String> {
+    let description = initial_data.strip_prefix(DESCRIPTION_TAG)?;
+    // The initial data may be padded with zeros when it is read from a file.
+    let len = description
+        .iter()
+        .rposition(|byte| *byte != 0)
+        .map_or(0, |pos| pos + 1);
+    Some(String::from_utf8_lossy(&description[..len]).into_owned())
+}
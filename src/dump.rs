@@ -0,0 +1,169 @@
+// SPDX-License-Identifier: MIT OR Apache-2.0
+//
+// Copyright (c) 2024 SUSE LLC
+//
+// Author: Roy Hopkins <roy.hopkins@suse.com>
+
+use std::collections::HashMap;
+use std::error::Error;
+use std::fs;
+
+use igvm::snp_defs::{SevSelector, SevVmsa};
+use igvm::{IgvmDirectiveHeader, IgvmFile, IgvmInitializationHeader, IgvmPlatformHeader};
+
+use crate::cmd_options::DumpOptions;
+use crate::description::parse_description;
+
+fn dump_selector(name: &str, selector: &SevSelector) {
+    println!(
+        "    {name:<4} selector={:#06X} base={:#X} limit={:#X} attrib={:#X}",
+        selector.selector, selector.base, selector.limit, selector.attrib
+    );
+}
+
+fn dump_vmsa(vmsa: &SevVmsa) {
+    dump_selector("cs", &vmsa.cs);
+    dump_selector("ds", &vmsa.ds);
+    dump_selector("es", &vmsa.es);
+    dump_selector("fs", &vmsa.fs);
+    dump_selector("gs", &vmsa.gs);
+    dump_selector("ss", &vmsa.ss);
+    dump_selector("tr", &vmsa.tr);
+    dump_selector("ldtr", &vmsa.ldtr);
+    dump_selector("gdtr", &vmsa.gdtr);
+    dump_selector("idtr", &vmsa.idtr);
+    println!(
+        "    rip={:#X} rsp={:#X} rflags={:#X}",
+        vmsa.rip, vmsa.rsp, vmsa.rflags
+    );
+    println!(
+        "    cr0={:#X} cr3={:#X} cr4={:#X} efer={:#X} xcr0={:#X}",
+        vmsa.cr0, vmsa.cr3, vmsa.cr4, vmsa.efer, vmsa.xcr0
+    );
+    println!(
+        "    rax={:#X} rbx={:#X} rcx={:#X} rdx={:#X} rsi={:#X} rdi={:#X} rbp={:#X}",
+        vmsa.rax, vmsa.rbx, vmsa.rcx, vmsa.rdx, vmsa.rsi, vmsa.rdi, vmsa.rbp
+    );
+    println!(
+        "    vmpl={} sev_features={:?}",
+        vmsa.vmpl, vmsa.sev_features
+    );
+}
+
+fn dump_platform(platform: &IgvmPlatformHeader) {
+    match platform {
+        IgvmPlatformHeader::SupportedPlatform(info) => println!(
+            "  SupportedPlatform mask={:#X} type={:?} version={} highest_vtl={} shared_gpa_boundary={:#X}",
+            info.compatibility_mask,
+            info.platform_type,
+            info.platform_version,
+            info.highest_vtl,
+            info.shared_gpa_boundary
+        ),
+    }
+}
+
+fn dump_initialization(initialization: &IgvmInitializationHeader) {
+    match initialization {
+        IgvmInitializationHeader::GuestPolicy {
+            policy,
+            compatibility_mask,
+        } => println!("  GuestPolicy mask={compatibility_mask:#X} policy={policy:#X}"),
+        other => println!("  {other:#X?}"),
+    }
+}
+
+fn dump_directive(directive: &IgvmDirectiveHeader) {
+    match directive {
+        IgvmDirectiveHeader::PageData {
+            gpa,
+            compatibility_mask,
+            flags,
+            data_type,
+            data,
+        } => println!(
+            "  PageData gpa={gpa:#X} mask={compatibility_mask:#X} type={data_type:?} flags={flags:?} size={:#X}",
+            data.len()
+        ),
+        IgvmDirectiveHeader::ParameterArea {
+            number_of_bytes,
+            parameter_area_index,
+            initial_data,
+        } => match parse_description(initial_data) {
+            Some(description) => println!(
+                "  ParameterArea index={parameter_area_index} size={number_of_bytes:#X} description={description:?}"
+            ),
+            None => println!(
+                "  ParameterArea index={parameter_area_index} size={number_of_bytes:#X} initial_data={:#X}",
+                initial_data.len()
+            ),
+        },
+        IgvmDirectiveHeader::SnpVpContext {
+            gpa,
+            compatibility_mask,
+            vp_index,
+            vmsa,
+        } => {
+            println!("  SnpVpContext gpa={gpa:#X} mask={compatibility_mask:#X} vp_index={vp_index}");
+            dump_vmsa(vmsa);
+        }
+        other => println!("  {other:X?}"),
+    }
+}
+
+/// Find pages that are populated more than once for the same platform.
+fn find_overlapping_pages(directives: &[IgvmDirectiveHeader]) -> Vec<(u64, u32)> {
+    let mut pages = HashMap::<u64, u32>::new();
+    let mut overlaps = Vec::new();
+    for directive in directives {
+        if let IgvmDirectiveHeader::PageData {
+            gpa,
+            compatibility_mask,
+            ..
+        } = directive
+        {
+            let mask = pages.entry(*gpa).or_insert(0);
+            if *mask & compatibility_mask != 0 {
+                overlaps.push((*gpa, *mask & compatibility_mask));
+            }
+            *mask |= compatibility_mask;
+        }
+    }
+    overlaps
+}
+
+pub fn dump(options: &DumpOptions) -> Result<(), Box<dyn Error>> {
+    let data = fs::read(&options.input).inspect_err(|_| {
+        eprintln!("Failed to open IGVM file {}", options.input);
+    })?;
+    let file = IgvmFile::new_from_binary(&data, None).inspect_err(|_| {
+        eprintln!("Failed to parse IGVM file {}", options.input);
+    })?;
+
+    for directive in file.directives() {
+        if let IgvmDirectiveHeader::ParameterArea { initial_data, .. } = directive {
+            if let Some(description) = parse_description(initial_data) {
+                println!("Description: {description}");
+            }
+        }
+    }
+    println!("Platforms:");
+    for platform in file.platforms() {
+        dump_platform(platform);
+    }
+    println!("Initialization:");
+    for initialization in file.initializations() {
+        dump_initialization(initialization);
+    }
+    println!("Directives:");
+    for directive in file.directives() {
+        dump_directive(directive);
+    }
+
+    for (gpa, compatibility_mask) in find_overlapping_pages(file.directives()) {
+        println!(
+            "Warning: Page at GPA {gpa:#X} is populated more than once for mask {compatibility_mask:#X}"
+        );
+    }
+    Ok(())
+}
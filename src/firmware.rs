@@ -0,0 +1,34 @@
+// SPDX-License-Identifier: MIT OR Apache-2.0
+//
+// Copyright (c) 2024 SUSE LLC
+//
+// Author: Roy Hopkins <roy.hopkins@suse.com>
+
+use igvm::IgvmDirectiveHeader;
+
+use crate::ovmf_firmware::{MetadataDesc, OvmfFwInfo};
+
+/// The image that the guest starts executing from, such as an OVMF firmware
+/// or a raw payload, along with the directives that populate it.
+pub trait FirmwareSource {
+    /// Hand over the directives that populate the image and any regions
+    /// described by its metadata, leaving none behind.
+    fn take_directives(&mut self) -> Vec<IgvmDirectiveHeader>;
+
+    /// The location of the image and the addresses described by its metadata.
+    /// Addresses that the image does not describe are zero.
+    fn fw_info(&self) -> &OvmfFwInfo;
+
+    /// The image as it is placed in guest memory.
+    fn data(&self) -> &[u8];
+
+    /// Metadata descriptors that were not used to build the directives.
+    fn ignored_metadata(&self) -> &[MetadataDesc] {
+        &[]
+    }
+
+    /// Problems found while parsing the image that did not prevent the build.
+    fn warnings(&self) -> &[String] {
+        &[]
+    }
+}
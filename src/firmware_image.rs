@@ -0,0 +1,86 @@
+// SPDX-License-Identifier: MIT OR Apache-2.0
+//
+// Copyright (c) 2024 SUSE LLC
+//
+// Author: Roy Hopkins <roy.hopkins@suse.com>
+
+use std::error::Error;
+use std::ops::Range;
+
+use igvm::IgvmDirectiveHeader;
+
+use crate::ovmf_firmware::OvmfFwInfo;
+use crate::page_data::{build_page_data, load_image, overlaps};
+
+/// Additional firmware images that are placed at fixed guest physical
+/// addresses alongside the OVMF firmware. These images are populated as-is:
+/// OVMF metadata is only ever parsed from the main firmware file.
+pub struct FirmwareImages {
+    ranges: Vec<(String, Range<u64>)>,
+    directives: Vec<IgvmDirectiveHeader>,
+}
+
+impl FirmwareImages {
+    pub fn parse(
+        images: &[(u64, String)],
+        compatibility_mask: u32,
+        fw_info: &OvmfFwInfo,
+        snp: bool,
+        shared_gpa_boundary: Option<u64>,
+    ) -> Result<Self, Box<dyn Error>> {
+        // On SEV-SNP the images must also avoid the pages that are populated
+        // from the firmware metadata.
+        let reserved = if snp {
+            fw_info.snp_regions()
+        } else {
+            vec![(
+                "firmware".to_string(),
+                fw_info.start as u64..fw_info.start as u64 + fw_info.size as u64,
+            )]
+        };
+        let mut ranges = Vec::<(String, Range<u64>)>::new();
+        let mut directives = Vec::new();
+
+        for (gpa, filename) in images {
+            let (data, range) = load_image(filename, *gpa, "firmware image")?;
+            // Private guest memory lies below the shared GPA boundary, so an
+            // image above it could never be accepted by the guest.
+            if let Some(boundary) = shared_gpa_boundary {
+                if range.end > boundary {
+                    return Err(format!(
+                        "The firmware image {filename} at {:#X}-{:#X} is above the shared GPA boundary {boundary:#X}",
+                        range.start, range.end
+                    )
+                    .into());
+                }
+            }
+            if let Some((name, other_range)) = reserved.iter().find(|(_, r)| overlaps(&range, r)) {
+                return Err(format!(
+                    "The firmware image {filename} at {:#X}-{:#X} overlaps the {name} at {:#X}-{:#X}",
+                    range.start, range.end, other_range.start, other_range.end
+                )
+                .into());
+            }
+            if let Some((other, other_range)) = ranges.iter().find(|(_, r)| overlaps(&range, r)) {
+                return Err(format!(
+                    "The firmware image {filename} at {:#X}-{:#X} overlaps the firmware image {other} at {:#X}-{:#X}",
+                    range.start, range.end, other_range.start, other_range.end
+                )
+                .into());
+            }
+            directives.append(&mut build_page_data(&data, *gpa, compatibility_mask));
+            ranges.push((filename.clone(), range));
+        }
+
+        Ok(Self { ranges, directives })
+    }
+
+    pub fn ranges(&self) -> &Vec<(String, Range<u64>)> {
+        &self.ranges
+    }
+
+    /// Hand the directives over to the caller, leaving none behind.
+    pub fn take_directives(&mut self) -> Vec<IgvmDirectiveHeader> {
+        std::mem::take(&mut self.directives)
+    }
+}
@@ -0,0 +1,194 @@
+// SPDX-License-Identifier: MIT OR Apache-2.0
+//
+// Copyright (c) 2024 SUSE LLC
+//
+// Author: Roy Hopkins <roy.hopkins@suse.com>
+
+use std::error::Error;
+use std::fs;
+
+use igvm::IgvmDirectiveHeader;
+use igvm_defs::{IGVM_VHS_SNP_ID_BLOCK_PUBLIC_KEY, IGVM_VHS_SNP_ID_BLOCK_SIGNATURE};
+use zerocopy::FromZeroes;
+
+use crate::cmd_options::IdKeyAlgorithm;
+
+// Size of the SEV-SNP ID block structure.
+const ID_BLOCK_SIZE: usize = 0x60;
+
+// Size of the SEV-SNP ID authentication information structure.
+const ID_AUTH_SIZE: usize = 0x1000;
+
+// Offsets of the fields within the ID authentication information structure.
+const ID_AUTH_ID_KEY_ALGO: usize = 0x000;
+const ID_AUTH_AUTH_KEY_ALGO: usize = 0x004;
+const ID_AUTH_ID_BLOCK_SIG: usize = 0x040;
+const ID_AUTH_ID_KEY: usize = 0x240;
+const ID_AUTH_ID_KEY_SIG: usize = 0x680;
+const ID_AUTH_AUTHOR_KEY: usize = 0x880;
+
+// Size of each ECDSA P-384 signature component and public key coordinate.
+const ECDSA_COMPONENT_SIZE: usize = 72;
+
+// Algorithm and curve identifiers defined in the SEV-SNP firmware ABI.
+const SNP_ALGO_ECDSA_P384_SHA384: u32 = 1;
+const SNP_CURVE_P384: u32 = 2;
+
+// Size of a public key in the ID authentication information, which is the
+// curve followed by the two coordinates and reserved space.
+const ECDSA_P384_PUBLIC_KEY_SIZE: usize = 0x404;
+
+/// The SEV-SNP ID block and its authentication information, loaded from the
+/// files provided on the command line.
+pub struct SnpIdBlock {
+    id_block: Vec<u8>,
+    id_auth: Vec<u8>,
+    algorithm: u32,
+}
+
+fn read_file(filename: &String, description: &str, size: usize) -> Result<Vec<u8>, Box<dyn Error>> {
+    let data = fs::read(filename).inspect_err(|_| {
+        eprintln!("Failed to open {description} file {}", filename);
+    })?;
+    if data.len() != size {
+        return Err(format!(
+            "The {description} file {} has size {:#X} but should be {:#X} bytes",
+            filename,
+            data.len(),
+            size
+        )
+        .into());
+    }
+    Ok(data)
+}
+
+fn read_u32(data: &[u8]) -> u32 {
+    u32::from_le_bytes(data[0..4].try_into().unwrap())
+}
+
+fn read_signature(data: &[u8]) -> Box<IGVM_VHS_SNP_ID_BLOCK_SIGNATURE> {
+    let mut signature = IGVM_VHS_SNP_ID_BLOCK_SIGNATURE::new_box_zeroed();
+    signature
+        .r_comp
+        .copy_from_slice(&data[0..ECDSA_COMPONENT_SIZE]);
+    signature
+        .s_comp
+        .copy_from_slice(&data[ECDSA_COMPONENT_SIZE..ECDSA_COMPONENT_SIZE * 2]);
+    signature
+}
+
+fn read_public_key(data: &[u8]) -> Box<IGVM_VHS_SNP_ID_BLOCK_PUBLIC_KEY> {
+    let mut public_key = IGVM_VHS_SNP_ID_BLOCK_PUBLIC_KEY::new_box_zeroed();
+    public_key.curve = read_u32(&data[0..4]);
+    public_key
+        .qx
+        .copy_from_slice(&data[4..4 + ECDSA_COMPONENT_SIZE]);
+    public_key
+        .qy
+        .copy_from_slice(&data[4 + ECDSA_COMPONENT_SIZE..4 + ECDSA_COMPONENT_SIZE * 2]);
+    public_key
+}
+
+/// Check that a key in the ID authentication information uses the selected
+/// algorithm and that its public key fits the space for it.
+fn validate_key(
+    id_auth: &[u8],
+    name: &str,
+    algo_offset: usize,
+    key_offset: usize,
+    algorithm: IdKeyAlgorithm,
+) -> Result<(), Box<dyn Error>> {
+    let (expected_algo, expected_curve, key_size) = match algorithm {
+        IdKeyAlgorithm::EcdsaP384 => (
+            SNP_ALGO_ECDSA_P384_SHA384,
+            SNP_CURVE_P384,
+            ECDSA_P384_PUBLIC_KEY_SIZE,
+        ),
+    };
+    let algo = read_u32(&id_auth[algo_offset..]);
+    if algo != expected_algo {
+        return Err(format!(
+            "The {name} algorithm {algo} in the ID authentication information does not match the {algorithm:?} algorithm {expected_algo}"
+        )
+        .into());
+    }
+    if key_offset + key_size > id_auth.len() {
+        return Err(format!(
+            "The {name} of {key_size:#X} bytes does not fit in the ID authentication information"
+        )
+        .into());
+    }
+    let curve = read_u32(&id_auth[key_offset..]);
+    if curve != expected_curve {
+        return Err(format!(
+            "The {name} curve {curve} does not match the {algorithm:?} curve {expected_curve}"
+        )
+        .into());
+    }
+    Ok(())
+}
+
+impl SnpIdBlock {
+    pub fn parse(
+        id_block: &String,
+        id_auth: &String,
+        algorithm: IdKeyAlgorithm,
+    ) -> Result<Self, Box<dyn Error>> {
+        let id_block = read_file(id_block, "ID block", ID_BLOCK_SIZE)?;
+        let id_auth = read_file(id_auth, "ID authentication", ID_AUTH_SIZE)?;
+        validate_key(
+            &id_auth,
+            "ID key",
+            ID_AUTH_ID_KEY_ALGO,
+            ID_AUTH_ID_KEY,
+            algorithm,
+        )?;
+        // The author key is optional and is disabled by a zero algorithm.
+        if read_u32(&id_auth[ID_AUTH_AUTH_KEY_ALGO..]) != 0 {
+            validate_key(
+                &id_auth,
+                "author key",
+                ID_AUTH_AUTH_KEY_ALGO,
+                ID_AUTH_AUTHOR_KEY,
+                algorithm,
+            )?;
+        }
+        let algorithm = match algorithm {
+            IdKeyAlgorithm::EcdsaP384 => SNP_ALGO_ECDSA_P384_SHA384,
+        };
+        Ok(Self {
+            id_block,
+            id_auth,
+            algorithm,
+        })
+    }
+
+    /// The guest policy that the ID block was signed with.
+    pub fn policy(&self) -> u64 {
+        u64::from_le_bytes(self.id_block[0x58..0x60].try_into().unwrap())
+    }
+
+    pub fn directive(&self, compatibility_mask: u32) -> IgvmDirectiveHeader {
+        let author_key_enabled = read_u32(&self.id_auth[ID_AUTH_AUTH_KEY_ALGO..]) != 0;
+        IgvmDirectiveHeader::SnpIdBlock {
+            compatibility_mask,
+            author_key_enabled: author_key_enabled as u8,
+            reserved: [0; 3],
+            ld: self.id_block[0x00..0x30].try_into().unwrap(),
+            family_id: self.id_block[0x30..0x40].try_into().unwrap(),
+            image_id: self.id_block[0x40..0x50].try_into().unwrap(),
+            version: read_u32(&self.id_block[0x50..]),
+            guest_svn: read_u32(&self.id_block[0x54..]),
+            id_key_algorithm: self.algorithm,
+            author_key_algorithm: if author_key_enabled {
+                self.algorithm
+            } else {
+                0
+            },
+            id_key_signature: read_signature(&self.id_auth[ID_AUTH_ID_BLOCK_SIG..]),
+            id_public_key: read_public_key(&self.id_auth[ID_AUTH_ID_KEY..]),
+            author_key_signature: read_signature(&self.id_auth[ID_AUTH_ID_KEY_SIG..]),
+            author_public_key: read_public_key(&self.id_auth[ID_AUTH_AUTHOR_KEY..]),
+        }
+    }
+}
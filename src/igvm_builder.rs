@@ -4,152 +4,1708 @@
 //
 // Author: Roy Hopkins <roy.hopkins@suse.com>
 
+use clap::ValueEnum;
+#[cfg(feature = "json")]
+use std::collections::BTreeMap;
 use std::error::Error;
-use std::fs::File;
-use std::io::Write;
+use std::fs::{self, File, OpenOptions};
+use std::io::{ErrorKind, Write};
+use std::ops::Range;
+use std::path::{Path, PathBuf};
 
-use clap::Parser;
 use igvm::{
-    IgvmDirectiveHeader, IgvmFile, IgvmInitializationHeader, IgvmPlatformHeader, IgvmRevision,
+    Arch, IgvmDirectiveHeader, IgvmFile, IgvmInitializationHeader, IgvmPlatformHeader, IgvmRevision,
 };
-use igvm_defs::{IgvmPlatformType, IGVM_VHS_SUPPORTED_PLATFORM};
+use igvm_defs::{
+    MemoryMapEntryType, IGVM_VHS_MEMORY_MAP_ENTRY, IGVM_VHS_PARAMETER, IGVM_VHS_PARAMETER_INSERT,
+    IGVM_VHS_SUPPORTED_PLATFORM, PAGE_SIZE_4K,
+};
+use zerocopy::AsBytes;
+
+use crate::acpi::load_acpi_tables;
+use crate::cmd_options::{self, BuildOptions, OutputFormat, Platform};
+use crate::description::{description_area, parse_description};
+use crate::firmware::FirmwareSource;
+use crate::firmware_image::FirmwareImages;
+use crate::id_block::SnpIdBlock;
+use crate::kernel::Kernel;
+use crate::manifest::build_manifest;
+use crate::measure::snp_launch_digest;
+#[cfg(feature = "json")]
+use crate::ovmf_firmware::MetadataDesc;
+use crate::ovmf_firmware::{check_reset_vector, OvmfFirmware, OvmfFwInfo, SnpPageOptions};
+use crate::page_data::{overlaps, populated_ranges, sparse_page_data};
+use crate::raw_payload::RawPayload;
+use crate::vmsa::{
+    construct_ap_vmsas, construct_bsp_vmsa, construct_native_bsp_context, default_cr4,
+    sev_features, BspOptions, VmsaOptions, DEFAULT_CR0, DEFAULT_EFER, DEFAULT_XCR0,
+};
+
+// The legacy x86 reset vector at which the BSP starts executing.
+const DEFAULT_BSP_RESET_ADDR: u32 = 0xfffffff0;
+
+// SEV and SEV-ES policy bit that requires the guest to run with SEV-ES.
+const SEV_POLICY_ES: u64 = 1 << 2;
 
-use crate::cmd_options::{self, CmdOptions};
-use crate::ovmf_firmware::OvmfFirmware;
-use crate::vmsa::{construct_ap_vmsa, construct_bsp_vmsa};
+// SEV-SNP policy bit that is reserved and must be set to one.
+const SNP_POLICY_RESERVED_MBO: u64 = 1 << 17;
 
-const COMPATIBILITY_MASK: u32 = 1;
+/// The build summary printed by --verbose with --format json.
+#[cfg(feature = "json")]
+#[derive(serde::Serialize)]
+struct VerboseInfo<'a> {
+    revision: u8,
+    firmware: &'a OvmfFwInfo,
+    ignored_metadata: &'a [MetadataDesc],
+    firmware_images: Vec<VerboseRegion>,
+    required_memory: Option<u32>,
+    sev_features: BTreeMap<String, u64>,
+    vmpl: u8,
+    shared_gpa_boundary: u64,
+    highest_vtl: u8,
+    platform_version: u16,
+    platforms: usize,
+    initialization: usize,
+    directives: usize,
+    pages: usize,
+}
+
+#[cfg(feature = "json")]
+#[derive(serde::Serialize)]
+struct VerboseRegion {
+    base: u64,
+    size: u64,
+}
 
 pub struct IgvmBuilder {
-    options: CmdOptions,
-    firmware: OvmfFirmware,
+    options: BuildOptions,
+    firmware: Box<dyn FirmwareSource>,
+    firmware_images: FirmwareImages,
+    kernel: Option<Kernel>,
+    acpi_tables: Option<Vec<u8>>,
+    id_block: Option<SnpIdBlock>,
+    compatibility_masks: Vec<(Platform, u32)>,
     platforms: Vec<IgvmPlatformHeader>,
     initialization: Vec<IgvmInitializationHeader>,
     directives: Vec<IgvmDirectiveHeader>,
+    built: bool,
 }
 
 impl IgvmBuilder {
-    pub fn new() -> Result<Self, Box<dyn Error>> {
-        let options = CmdOptions::parse();
-        let firmware =
-            OvmfFirmware::parse(&options.firmware, COMPATIBILITY_MASK, options.platform)?;
+    pub fn new(options: BuildOptions) -> Result<Self, Box<dyn Error>> {
+        options.validate()?;
+        Self::check_outputs_writable(&options)?;
+        if let Some(rsp) = options.bsp_rsp {
+            if rsp & 0xf != 0 {
+                options.warn(&format!("The BSP RSP {rsp:#X} is not 16-byte aligned"));
+            }
+        }
+        if options.no_vmsa {
+            options.warn(
+                "No VMSAs are generated. The VMM must initialize the vCPU state of SEV-ES and SEV-SNP guests",
+            );
+        }
+        let compatibility_masks =
+            Self::assign_compatibility_masks(&options.platform, options.compat_mask);
+        let cpuid_data = options
+            .cpuid_page
+            .as_ref()
+            .map(|filename| Self::read_page_file(filename, "CPUID page"))
+            .transpose()?;
+        let secrets_data = options
+            .secrets_data
+            .as_ref()
+            .map(|filename| Self::read_page_file(filename, "secrets data"))
+            .transpose()?;
+        let firmware: Box<dyn FirmwareSource> = match (&options.firmware, &options.raw_payload) {
+            (Some(filename), _) => Box::new(OvmfFirmware::parse(
+                filename,
+                &compatibility_masks,
+                options.pad_firmware,
+                options.firmware_base,
+                cpuid_data.as_deref(),
+                SnpPageOptions {
+                    secrets_page: options.secrets_gpa,
+                    caa_page: options.caa_gpa,
+                    cpuid_page: options.cpuid_gpa,
+                    skip_pages: options.no_fw_metadata_pages,
+                    coalesce_zero_pages: options.coalesce_zero_pages,
+                    allow_overlap: options.allow_overlap,
+                    unmeasured_caa: options.unmeasured_caa,
+                    svsm_region: options.svsm_region,
+                    prevalidated: &options.prevalidate,
+                    unmeasured_prevalidated: &options.unmeasured_prevalidated,
+                    require_metadata: options.require_sev_metadata,
+                    secrets_data: secrets_data.as_deref(),
+                },
+                options.expect_firmware_sha256.as_ref(),
+            )?),
+            (None, Some((gpa, filename))) => Box::new(RawPayload::parse(
+                filename,
+                *gpa,
+                Self::all_platforms_mask(&compatibility_masks),
+            )?),
+            (None, None) => return Err("No firmware or raw payload was provided".into()),
+        };
+        for warning in firmware.warnings() {
+            options.warn(warning);
+        }
+        if options.check_reset_vector {
+            if let Err(e) = check_reset_vector(firmware.data()) {
+                options.warn(&e);
+            }
+        }
+        let firmware_images = FirmwareImages::parse(
+            &options.firmware_at,
+            Self::all_platforms_mask(&compatibility_masks),
+            firmware.fw_info(),
+            options.platform.contains(&Platform::SevSnp),
+            options.shared_gpa_boundary,
+        )?;
+        let acpi_tables = match &options.acpi {
+            Some(dir) => {
+                let tables = load_acpi_tables(dir)?;
+                if tables.is_empty() {
+                    options.warn(&format!("No ACPI tables found in {dir}"));
+                    None
+                } else {
+                    Some(tables)
+                }
+            }
+            None => None,
+        };
+        let kernel = match &options.kernel {
+            Some(kernel) => Some(Kernel::parse(
+                kernel,
+                options.kernel_gpa,
+                options.initrd.as_ref(),
+                options.initrd_gpa,
+                Self::all_platforms_mask(&compatibility_masks),
+                &Self::populated_regions(
+                    &options,
+                    firmware.fw_info(),
+                    &firmware_images,
+                    acpi_tables.as_ref(),
+                ),
+            )?),
+            None => None,
+        };
+        let id_block = match (&options.id_block, &options.id_auth) {
+            (Some(id_block), Some(id_auth)) => Some(SnpIdBlock::parse(
+                id_block,
+                id_auth,
+                options.id_key_algorithm,
+            )?),
+            _ => None,
+        };
         Ok(Self {
             options,
             firmware,
+            firmware_images,
+            kernel,
+            acpi_tables,
+            id_block,
+            compatibility_masks,
             platforms: vec![],
             initialization: vec![],
             directives: vec![],
+            built: false,
         })
     }
 
-    pub fn build(mut self) -> Result<(), Box<dyn Error>> {
+    /// Build the IGVM headers from the parsed inputs. The page directives are
+    /// moved out of the inputs rather than copied, so a builder can only be
+    /// built once. The headers can be inspected with `platforms`,
+    /// `initialization` and `directives` until they are written.
+    pub fn build(&mut self) -> Result<(), Box<dyn Error>> {
+        if self.built {
+            return Err("The IGVM file has already been built".into());
+        }
+        self.built = true;
+
         self.build_initialization()?;
         self.build_directives()?;
+        self.build_parameters()?;
+        self.check_vmsa_gpa()?;
         self.build_platforms();
 
-        // Separate the directive pages out from the others so we can populate them last.
-        let (mut pages, others): (Vec<_>, Vec<_>) = self
-            .directives
-            .iter()
-            .cloned()
-            .partition(Self::filter_pages);
-
-        self.directives = others;
-        self.directives.append(&mut pages);
+        // Move the directive pages after the others so we can populate them
+        // last, followed by the VP contexts in ascending vp_index order. The
+        // sort is stable so the order within each group is kept, unless the
+        // pages are also sorted by GPA.
+        let sort_pages = self.options.sort_pages;
+        self.directives
+            .sort_by_key(|directive| Self::directive_order(directive, sort_pages));
 
         if self.options.verbose {
-            let fw_info = self.firmware.get_fw_info();
-            println!("{fw_info:#X?}");
+            let fw_info = self.firmware.fw_info();
+            match self.options.format {
+                OutputFormat::Text => {
+                    self.print_verbose(&format!("{fw_info:#X?}"));
+                    for desc in self.firmware.ignored_metadata() {
+                        self.print_verbose(&format!(
+                            "Ignored OVMF SEV metadata descriptor: type={:#X} base={:#X} len={:#X}",
+                            desc.metadata_type, desc.base, desc.len
+                        ));
+                    }
+                    if let Some(memory_size) = self.required_memory_size()? {
+                        self.print_verbose(&format!("Required memory: 0x0-{memory_size:#X}"));
+                    }
+                    if fw_info.svsm_region.size != 0 {
+                        self.print_verbose(&format!(
+                            "SVSM region: {:#X}-{:#X}",
+                            fw_info.svsm_region.base,
+                            fw_info.svsm_region.base as u64 + fw_info.svsm_region.size as u64
+                        ));
+                    }
+                    for (filename, range) in self.firmware_images.ranges() {
+                        self.print_verbose(&format!(
+                            "Firmware image {filename}: {:#X}-{:#X}",
+                            range.start, range.end
+                        ));
+                    }
+                    match self.revision() {
+                        IgvmRevision::V1 => self.print_verbose("IGVM revision: 1"),
+                        IgvmRevision::V2 { arch, page_size } => self.print_verbose(&format!(
+                            "IGVM revision: 2 arch={arch:?} page_size={page_size:#X}"
+                        )),
+                    }
+                    if self.options.vmpl != 0 {
+                        self.print_verbose(&format!("SEV-SNP VMPL: {}", self.options.vmpl));
+                    }
+                    if self
+                        .compatibility_masks
+                        .iter()
+                        .any(|(platform, _)| matches!(platform, Platform::SevEs | Platform::SevSnp))
+                        && !self.options.no_vmsa
+                    {
+                        let vmsa_options = self.vmsa_options();
+                        self.print_verbose(&format!(
+                            "VMSA control registers: cr0={:#X} cr4={:#X} efer={:#X} xcr0={:#X}",
+                            vmsa_options.cr0,
+                            vmsa_options.cr4,
+                            vmsa_options.efer,
+                            vmsa_options.xcr0
+                        ));
+                    }
+                    self.print_verbose(&format!(
+                        "Platform version: {}",
+                        self.options.platform_version
+                    ));
+                    for (platform, _) in &self.compatibility_masks {
+                        let highest_vtl = self.highest_vtl(*platform);
+                        if highest_vtl != 0 {
+                            self.print_verbose(&format!("{platform:?} highest VTL: {highest_vtl}"));
+                        }
+                        let boundary = self.shared_gpa_boundary(*platform);
+                        if boundary != 0 {
+                            self.print_verbose(&format!(
+                                "{platform:?} shared GPA boundary: {boundary:#X}"
+                            ));
+                        }
+                    }
+                    for (platform, _) in &self.compatibility_masks {
+                        if matches!(platform, Platform::SevEs | Platform::SevSnp) {
+                            let features = sev_features(*platform, &self.options.sev_feature);
+                            self.print_verbose(&format!(
+                                "{platform:?} SEV features: {:#X} {features:?}",
+                                u64::from(features)
+                            ));
+                        }
+                    }
+                }
+                OutputFormat::Json => eprintln!("{}", self.verbose_json()?),
+            }
+        }
+
+        if self.options.measure {
+            self.print_launch_digest()?;
+        }
+        Ok(())
+    }
+
+    /// The platform headers produced by the last call to `build`.
+    #[cfg_attr(not(feature = "json"), allow(dead_code))]
+    pub fn platforms(&self) -> &[IgvmPlatformHeader] {
+        &self.platforms
+    }
+
+    /// The initialization headers produced by the last call to `build`.
+    pub fn initialization(&self) -> &[IgvmInitializationHeader] {
+        &self.initialization
+    }
+
+    /// The directive headers produced by the last call to `build`, in the
+    /// order they are written to the file.
+    pub fn directives(&self) -> &[IgvmDirectiveHeader] {
+        &self.directives
+    }
+
+    /// Write the IGVM file built by `build`, and the manifest if requested, to
+    /// the output. The headers are moved into the file so `build` must be
+    /// called again before another file is written.
+    pub fn write(&mut self) -> Result<(), Box<dyn Error>> {
+        // The manifest is built before the headers are moved into the file.
+        let manifest = self
+            .options
+            .manifest
+            .as_ref()
+            .filter(|_| !self.options.check)
+            .map(|filename| {
+                let manifest = build_manifest(
+                    &self.options,
+                    self.firmware.as_ref(),
+                    &self.compatibility_masks,
+                    self.initialization(),
+                )?;
+                Ok::<_, Box<dyn Error>>((filename.clone(), manifest))
+            })
+            .transpose()?;
+        self.write_igvm()?;
+        if let Some((filename, manifest)) = manifest {
+            Self::write_output(&filename, manifest.as_bytes(), self.options.mode)?;
+        }
+        Ok(())
+    }
+
+    /// Serialize the IGVM file and write it to the output, or print a summary
+    /// if only checking the inputs.
+    fn write_igvm(&mut self) -> Result<(), Box<dyn Error>> {
+        if let Some(output_dir) = &self.options.output_dir {
+            if !self.options.check {
+                for (platform, compatibility_mask) in &self.compatibility_masks {
+                    let file = self.platform_file(*compatibility_mask)?;
+                    let mut binary_file = Vec::new();
+                    self.print_progress(&format!(
+                        "Serializing {} directives for {platform:?}",
+                        file.directives().len()
+                    ));
+                    file.serialize(&mut binary_file)?;
+                    self.print_progress(&format!("Serialized {:#X} bytes", binary_file.len()));
+                    if self.options.self_check {
+                        Self::self_check(&file, &binary_file)?;
+                    }
+                    let filename = Self::platform_filename(output_dir, *platform);
+                    Self::write_output(
+                        &filename.to_string_lossy(),
+                        &binary_file,
+                        self.options.mode,
+                    )?;
+                }
+                return Ok(());
+            }
         }
 
         let file = IgvmFile::new(
-            IgvmRevision::V1,
-            self.platforms,
-            self.initialization,
-            self.directives,
+            self.revision(),
+            std::mem::take(&mut self.platforms),
+            std::mem::take(&mut self.initialization),
+            std::mem::take(&mut self.directives),
         )
-        .map_err(|e| {
+        .inspect_err(|_| {
             eprintln!("Failed to create output file");
-            e
         })?;
+        let file = match &self.options.append_platform {
+            Some(filename) => Self::append_platforms(filename, file)?,
+            None => file,
+        };
 
         let mut binary_file = Vec::new();
+        self.print_progress(&format!(
+            "Serializing {} directives",
+            file.directives().len()
+        ));
         file.serialize(&mut binary_file)?;
+        self.print_progress(&format!("Serialized {:#X} bytes", binary_file.len()));
+        if self.options.self_check {
+            Self::self_check(&file, &binary_file)?;
+        }
+
+        let filename = match &self.options.output {
+            Some(filename) if !self.options.check => filename,
+            _ => {
+                if !self.options.quiet {
+                    Self::print_summary(&file, self.firmware.fw_info(), binary_file.len());
+                }
+                return Ok(());
+            }
+        };
+
+        if self.options.output_to_stdout() {
+            let mut output = std::io::stdout().lock();
+            output.write_all(binary_file.as_slice()).inspect_err(|_| {
+                eprintln!("Failed to write output file {}", filename);
+            })?;
+            output.flush()?;
+            return Ok(());
+        }
+        Self::write_output(filename, &binary_file, self.options.mode)
+    }
+
+    /// Merge the newly built file into an existing IGVM file. The platforms of
+    /// the new file are given compatibility mask bits that are unused in the
+    /// existing file, and directives that are identical in both files are
+    /// shared by combining their masks.
+    fn append_platforms(filename: &str, file: IgvmFile) -> Result<IgvmFile, Box<dyn Error>> {
+        let data = fs::read(filename).inspect_err(|_| {
+            eprintln!("Failed to open IGVM file {}", filename);
+        })?;
+        let mut existing = IgvmFile::new_from_binary(&data, None)
+            .map_err(|e| format!("Failed to parse IGVM file {filename}: {e}"))?;
+        existing
+            .merge(file)
+            .map_err(|e| format!("Failed to append the platforms to {filename}: {e}"))?;
+        Ok(existing)
+    }
 
-        let mut output = File::create(&self.options.output).map_err(|e| {
-            eprintln!("Failed to create output file {}", self.options.output);
-            e
+    /// Deserialize the binary IGVM file and check that it has the same number
+    /// of headers of each kind as the file it was serialized from.
+    fn self_check(file: &IgvmFile, binary_file: &[u8]) -> Result<(), Box<dyn Error>> {
+        let parsed = IgvmFile::new_from_binary(binary_file, None).map_err(|e| {
+            format!("Self-check failed: the serialized IGVM file cannot be parsed: {e}")
         })?;
-        output.write_all(binary_file.as_slice()).map_err(|e| {
-            eprintln!("Failed to write output file {}", self.options.output);
-            e
+        for (name, built, found) in [
+            ("platform", file.platforms().len(), parsed.platforms().len()),
+            (
+                "initialization",
+                file.initializations().len(),
+                parsed.initializations().len(),
+            ),
+            (
+                "directive",
+                file.directives().len(),
+                parsed.directives().len(),
+            ),
+        ] {
+            if built != found {
+                return Err(format!(
+                    "Self-check failed: {built} {name} headers were built but the serialized IGVM file contains {found}"
+                )
+                .into());
+            }
+        }
+        Ok(())
+    }
+
+    /// Read a file whose contents are placed in a single page.
+    fn read_page_file(filename: &str, description: &str) -> Result<Vec<u8>, Box<dyn Error>> {
+        let data = fs::read(filename).inspect_err(|_| {
+            eprintln!("Failed to open {description} file {}", filename);
         })?;
+        if data.len() > PAGE_SIZE_4K as usize {
+            return Err(
+                format!("The {description} file {} is larger than a page", filename).into(),
+            );
+        }
+        Ok(data)
+    }
+
+    /// The name of the IGVM file for a platform in the output directory.
+    fn platform_filename(output_dir: &str, platform: Platform) -> PathBuf {
+        let name = platform
+            .to_possible_value()
+            .map(|value| value.get_name().to_string())
+            .unwrap_or_else(|| format!("{platform:?}"));
+        Path::new(output_dir).join(format!("{name}.igvm"))
+    }
+
+    /// Check that each output can be written before the inputs are processed,
+    /// so a long build does not fail only when the output is written.
+    fn check_outputs_writable(options: &BuildOptions) -> Result<(), Box<dyn Error>> {
+        if options.check {
+            return Ok(());
+        }
+        let mut filenames = Vec::new();
+        if let Some(output_dir) = &options.output_dir {
+            filenames.extend(options.platform.iter().map(|platform| {
+                Self::platform_filename(output_dir, *platform)
+                    .to_string_lossy()
+                    .into_owned()
+            }));
+        } else if let Some(output) = options
+            .output
+            .as_ref()
+            .filter(|_| !options.output_to_stdout())
+        {
+            filenames.push(output.clone());
+        }
+        filenames.extend(options.manifest.iter().cloned());
+        for filename in filenames {
+            Self::probe_writable(&filename)
+                .map_err(|e| format!("The output file {filename} cannot be written: {e}"))?;
+        }
         Ok(())
     }
 
-    fn build_platforms(&mut self) {
-        let platform_type = match self.options.platform {
-            cmd_options::Platform::Sev => IgvmPlatformType::SEV,
-            cmd_options::Platform::SevEs => IgvmPlatformType::SEV_ES,
-            cmd_options::Platform::SevSnp => IgvmPlatformType::SEV_SNP,
-            cmd_options::Platform::Native => IgvmPlatformType::NATIVE,
-        };
-        self.platforms.push(IgvmPlatformHeader::SupportedPlatform(
-            IGVM_VHS_SUPPORTED_PLATFORM {
-                compatibility_mask: COMPATIBILITY_MASK,
-                highest_vtl: 0,
-                platform_type,
-                platform_version: 1,
-                shared_gpa_boundary: 0,
+    /// Check that a file can be created alongside `filename` by creating a new
+    /// file with a unique name and removing it again. Existing files are never
+    /// opened, so only the probe file that was created here is removed.
+    fn probe_writable(filename: &str) -> std::io::Result<()> {
+        let (probe, _) = Self::create_unique(filename, "probe")?;
+        fs::remove_file(&probe)
+    }
+
+    /// Create a new file alongside `filename` with a name that includes the
+    /// process ID and an attempt number, so an existing file is never opened.
+    fn create_unique(filename: &str, suffix: &str) -> std::io::Result<(String, File)> {
+        let mut attempt = 0;
+        loop {
+            let unique = format!("{filename}.{}.{attempt}.{suffix}", std::process::id());
+            match OpenOptions::new()
+                .write(true)
+                .create_new(true)
+                .open(&unique)
+            {
+                Ok(file) => return Ok((unique, file)),
+                Err(e) if e.kind() == ErrorKind::AlreadyExists && attempt < 16 => attempt += 1,
+                Err(e) => return Err(e),
+            }
+        }
+    }
+
+    /// Write the output to a new temporary file and rename it over the target,
+    /// so a failed write never replaces a previous output with a partial file.
+    fn write_output(filename: &str, data: &[u8], mode: Option<u32>) -> Result<(), Box<dyn Error>> {
+        let (temp_filename, output) = Self::create_unique(filename, "tmp").inspect_err(|_| {
+            eprintln!("Failed to create output file {}", filename);
+        })?;
+        let result = Self::write_file(output, filename, data, mode).and_then(|_| {
+            fs::rename(&temp_filename, filename).inspect_err(|_| {
+                eprintln!("Failed to write output file {}", filename);
+            })?;
+            Ok(())
+        });
+        if result.is_err() {
+            let _ = fs::remove_file(&temp_filename);
+        }
+        result
+    }
+
+    fn write_file(
+        mut output: File,
+        filename: &str,
+        data: &[u8],
+        mode: Option<u32>,
+    ) -> Result<(), Box<dyn Error>> {
+        output.write_all(data).inspect_err(|_| {
+            eprintln!("Failed to write output file {}", filename);
+        })?;
+        output.flush()?;
+        #[cfg(unix)]
+        if let Some(mode) = mode {
+            use std::os::unix::fs::PermissionsExt;
+            output
+                .set_permissions(fs::Permissions::from_mode(mode))
+                .inspect_err(|_| {
+                    eprintln!("Failed to set the mode of output file {}", filename);
+                })?;
+        }
+        #[cfg(not(unix))]
+        let _ = mode;
+        Ok(())
+    }
+
+    /// Build an IGVM file for a single platform from the headers that apply to
+    /// its compatibility mask. The platform is the only one in the file so it
+    /// is given the first compatibility mask bit.
+    fn platform_file(&self, compatibility_mask: u32) -> Result<IgvmFile, Box<dyn Error>> {
+        let platforms = self
+            .platforms
+            .iter()
+            .filter_map(|platform| match platform {
+                IgvmPlatformHeader::SupportedPlatform(info) => {
+                    (info.compatibility_mask == compatibility_mask).then_some(
+                        IgvmPlatformHeader::SupportedPlatform(IGVM_VHS_SUPPORTED_PLATFORM {
+                            compatibility_mask: 1,
+                            ..*info
+                        }),
+                    )
+                }
+            })
+            .collect();
+        let initialization = self
+            .initialization
+            .iter()
+            .filter_map(|initialization| {
+                let mut initialization = initialization.clone();
+                if let IgvmInitializationHeader::GuestPolicy {
+                    compatibility_mask: mask,
+                    ..
+                } = &mut initialization
+                {
+                    if *mask & compatibility_mask == 0 {
+                        return None;
+                    }
+                    *mask = 1;
+                }
+                Some(initialization)
+            })
+            .collect();
+        let directives: Vec<_> = self
+            .directives
+            .iter()
+            .filter_map(|directive| {
+                let mut directive = directive.clone();
+                let mask = match &mut directive {
+                    IgvmDirectiveHeader::PageData {
+                        compatibility_mask, ..
+                    }
+                    | IgvmDirectiveHeader::SnpVpContext {
+                        compatibility_mask, ..
+                    }
+                    | IgvmDirectiveHeader::X64NativeVpContext {
+                        compatibility_mask, ..
+                    }
+                    | IgvmDirectiveHeader::RequiredMemory {
+                        compatibility_mask, ..
+                    }
+                    | IgvmDirectiveHeader::SnpIdBlock {
+                        compatibility_mask, ..
+                    } => Some(compatibility_mask),
+                    IgvmDirectiveHeader::ParameterInsert(insert) => {
+                        Some(&mut insert.compatibility_mask)
+                    }
+                    _ => None,
+                };
+                if let Some(mask) = mask {
+                    if *mask & compatibility_mask == 0 {
+                        return None;
+                    }
+                    *mask = 1;
+                }
+                Some(directive)
+            })
+            .collect();
+
+        // Drop parameter areas that are only inserted for other platforms. The
+        // description area is never inserted and applies to every platform.
+        let inserted: Vec<u32> = directives
+            .iter()
+            .filter_map(|directive| match directive {
+                IgvmDirectiveHeader::ParameterInsert(insert) => Some(insert.parameter_area_index),
+                _ => None,
+            })
+            .collect();
+        let directives = directives
+            .into_iter()
+            .filter(|directive| match directive {
+                IgvmDirectiveHeader::ParameterArea {
+                    parameter_area_index,
+                    initial_data,
+                    ..
+                } => {
+                    inserted.contains(parameter_area_index)
+                        || parse_description(initial_data).is_some()
+                }
+                IgvmDirectiveHeader::CommandLine(parameter)
+                | IgvmDirectiveHeader::VpCount(parameter) => {
+                    inserted.contains(&parameter.parameter_area_index)
+                }
+                _ => true,
+            })
+            .collect();
+        IgvmFile::new(self.revision(), platforms, initialization, directives)
+            .inspect_err(|_| {
+                eprintln!("Failed to create output file");
+            })
+            .map_err(|e| e.into())
+    }
+
+    /// Revision 2 adds the architecture and page size to the file header.
+    /// Only x64 with 4K pages is supported.
+    fn revision(&self) -> IgvmRevision {
+        match self.options.revision {
+            1 => IgvmRevision::V1,
+            _ => IgvmRevision::V2 {
+                arch: Arch::X64,
+                page_size: PAGE_SIZE_4K as u32,
             },
-        ));
+        }
+    }
+
+    fn print_launch_digest(&self) -> Result<(), Box<dyn Error>> {
+        for (platform, compatibility_mask) in &self.compatibility_masks {
+            if *platform == Platform::SevSnp {
+                let digest = snp_launch_digest(self.directives(), *compatibility_mask)?;
+                let digest = digest
+                    .iter()
+                    .map(|b| format!("{b:02x}"))
+                    .collect::<String>();
+                self.print_verbose(&format!("SEV-SNP launch digest: {digest}"));
+            }
+        }
+        Ok(())
+    }
+
+    fn print_summary(file: &IgvmFile, fw_info: &OvmfFwInfo, file_size: usize) {
+        let page_count = file
+            .directives()
+            .iter()
+            .filter(|directive| matches!(directive, IgvmDirectiveHeader::PageData { .. }))
+            .count();
+        println!("Platforms: {}", file.platforms().len());
+        println!("Initialization headers: {}", file.initializations().len());
+        println!("Directives: {}", file.directives().len());
+        println!("Pages: {page_count}");
+        println!(
+            "Firmware range: {:#X}-{:#X}",
+            fw_info.start,
+            fw_info.start as u64 + fw_info.size as u64
+        );
+        println!("IGVM file size: {file_size:#X}");
+    }
+
+    #[cfg(feature = "json")]
+    fn verbose_json(&self) -> Result<String, Box<dyn Error>> {
+        let sev_features = self
+            .compatibility_masks
+            .iter()
+            .filter(|(platform, _)| matches!(platform, Platform::SevEs | Platform::SevSnp))
+            .map(|(platform, _)| {
+                (
+                    format!("{platform:?}"),
+                    u64::from(sev_features(*platform, &self.options.sev_feature)),
+                )
+            })
+            .collect();
+        let firmware_images = self
+            .firmware_images
+            .ranges()
+            .iter()
+            .map(|(_, range)| VerboseRegion {
+                base: range.start,
+                size: range.end - range.start,
+            })
+            .collect();
+        let verbose = VerboseInfo {
+            revision: self.options.revision,
+            firmware: self.firmware.fw_info(),
+            ignored_metadata: self.firmware.ignored_metadata(),
+            firmware_images,
+            required_memory: self.required_memory_size()?,
+            sev_features,
+            vmpl: self.options.vmpl,
+            shared_gpa_boundary: self.options.shared_gpa_boundary.unwrap_or(0),
+            highest_vtl: self.options.highest_vtl,
+            platform_version: self.options.platform_version,
+            platforms: self.platforms().len(),
+            initialization: self.initialization().len(),
+            directives: self.directives().len(),
+            pages: self
+                .directives
+                .iter()
+                .filter(|directive| matches!(directive, IgvmDirectiveHeader::PageData { .. }))
+                .count(),
+        };
+        Ok(serde_json::to_string(&verbose)?)
+    }
+
+    #[cfg(not(feature = "json"))]
+    fn verbose_json(&self) -> Result<String, Box<dyn Error>> {
+        Err("JSON output is not supported: buildigvm was built without the json feature".into())
+    }
+
+    fn print_progress(&self, message: &str) {
+        // Progress always goes to stderr so it cannot mix with the output.
+        if self.options.progress {
+            eprintln!("{message}");
+        }
+    }
+
+    fn print_verbose(&self, message: &str) {
+        // Keep stdout free for the IGVM file when writing to stdout.
+        if self.options.output_to_stdout() {
+            eprintln!("{message}");
+        } else {
+            println!("{message}");
+        }
+    }
+
+    /// Each platform in the IGVM file is identified by a distinct bit in the
+    /// compatibility mask. Duplicate platforms are collapsed into one entry.
+    fn assign_compatibility_masks(
+        platforms: &[Platform],
+        compat_mask: Option<u32>,
+    ) -> Vec<(Platform, u32)> {
+        let mut platforms = platforms.to_vec();
+        platforms.sort();
+        platforms.dedup();
+        // An explicit mask provides the bits in ascending order.
+        let bits = (0..u32::BITS)
+            .map(|bit| 1u32 << bit)
+            .filter(|bit| compat_mask.is_none_or(|mask| mask & bit != 0));
+        platforms.into_iter().zip(bits).collect()
+    }
+
+    fn all_platforms_mask(compatibility_masks: &[(Platform, u32)]) -> u32 {
+        compatibility_masks
+            .iter()
+            .fold(0, |mask, (_, platform_mask)| mask | platform_mask)
+    }
+
+    fn build_platforms(&mut self) {
+        for (platform, compatibility_mask) in &self.compatibility_masks {
+            let platform_type = platform.platform_type();
+            self.platforms.push(IgvmPlatformHeader::SupportedPlatform(
+                IGVM_VHS_SUPPORTED_PLATFORM {
+                    compatibility_mask: *compatibility_mask,
+                    highest_vtl: self.highest_vtl(*platform),
+                    platform_type,
+                    platform_version: self.options.platform_version,
+                    shared_gpa_boundary: self.shared_gpa_boundary(*platform),
+                },
+            ));
+        }
+    }
+
+    fn vmsa_options(&self) -> VmsaOptions<'_> {
+        let xcr0 = self.options.xcr0.unwrap_or(DEFAULT_XCR0);
+        VmsaOptions {
+            features: &self.options.sev_feature,
+            vmpl: self.options.vmpl,
+            cr0: self.options.cr0.unwrap_or(DEFAULT_CR0),
+            cr4: self.options.cr4.unwrap_or(default_cr4(xcr0)),
+            efer: self.options.efer.unwrap_or(DEFAULT_EFER),
+            xcr0,
+        }
+    }
+
+    /// Only SEV-SNP and TDX can host a guest with more than one VTL, using
+    /// VMPLs and TD partitioning respectively. No additional initialization
+    /// headers are needed to describe them.
+    fn highest_vtl(&self, platform: Platform) -> u8 {
+        match platform {
+            Platform::SevSnp | Platform::Tdx => self.options.highest_vtl,
+            _ => 0,
+        }
+    }
+
+    /// The shared GPA boundary only applies to isolated platforms.
+    fn shared_gpa_boundary(&self, platform: Platform) -> u64 {
+        match platform {
+            Platform::Native => 0,
+            _ => self.options.shared_gpa_boundary.unwrap_or(0),
+        }
     }
 
     fn build_directives(&mut self) -> Result<(), Box<dyn Error>> {
+        let page_count = |directives: &[IgvmDirectiveHeader]| {
+            directives
+                .iter()
+                .filter(|directive| matches!(directive, IgvmDirectiveHeader::PageData { .. }))
+                .count()
+        };
+        // Native loaders zero-initialize guest memory so zero pages can be
+        // omitted entirely for the native platform.
+        let mut firmware_directives = if self.options.sparse {
+            let native_mask = self
+                .compatibility_masks
+                .iter()
+                .find(|(platform, _)| *platform == Platform::Native)
+                .map_or(0, |(_, mask)| *mask);
+            sparse_page_data(self.firmware.take_directives(), native_mask)
+        } else {
+            self.firmware.take_directives()
+        };
+        let mut image_directives = self.firmware_images.take_directives();
+        let mut kernel_directives = self
+            .kernel
+            .as_mut()
+            .map_or(vec![], |kernel| kernel.take_directives());
+        let total_pages = page_count(&firmware_directives)
+            + page_count(&image_directives)
+            + page_count(&kernel_directives);
+        let mut pages = 0;
+
         // Populate firmware directives.
-        self.directives
-            .extend_from_slice(self.firmware.directives());
-
-        match self.options.platform {
-            cmd_options::Platform::SevEs | cmd_options::Platform::SevSnp => {
-                // Build VMSAs for the required number of processors
-                self.directives.push(construct_bsp_vmsa(
-                    0xFFFFFFFFF000,
-                    COMPATIBILITY_MASK,
-                    self.options.platform,
-                )?);
-                for vp in 1..self.options.cpucount {
-                    self.directives.push(construct_ap_vmsa(
-                        0xFFFFFFFFF000,
-                        COMPATIBILITY_MASK,
-                        self.options.platform,
-                        self.firmware.get_fw_info().reset_addr,
-                        vp,
+        pages += page_count(&firmware_directives);
+        self.directives.append(&mut firmware_directives);
+        let firmware_pages = pages;
+        self.print_progress(&format!("Generated {pages}/{total_pages} page directives"));
+
+        // Describe the low guest RAM that the image expects to be present.
+        if let Some(memory_size) = self.required_memory_size()? {
+            self.directives.push(IgvmDirectiveHeader::RequiredMemory {
+                gpa: 0,
+                compatibility_mask: Self::all_platforms_mask(&self.compatibility_masks),
+                number_of_bytes: memory_size,
+                vtl2_protectable: false,
+            });
+        }
+
+        // Populate any additional firmware images.
+        pages += page_count(&image_directives);
+        self.directives.append(&mut image_directives);
+
+        // Populate kernel and initrd directives.
+        pages += page_count(&kernel_directives);
+        self.directives.append(&mut kernel_directives);
+        if pages > firmware_pages {
+            self.print_progress(&format!("Generated {pages}/{total_pages} page directives"));
+        }
+
+        let total_vmsas = self
+            .compatibility_masks
+            .iter()
+            .filter(|(platform, _)| {
+                matches!(platform, Platform::SevEs | Platform::SevSnp) && !self.options.no_vmsa
+            })
+            .count()
+            * self.options.cpucount as usize;
+        let mut vmsas = 0;
+
+        let vmsa_gpa = self.vmsa_gpa()?;
+        for (platform, compatibility_mask) in &self.compatibility_masks {
+            match platform {
+                cmd_options::Platform::SevEs | cmd_options::Platform::SevSnp
+                    if !self.options.no_vmsa =>
+                {
+                    // Build VMSAs for the required number of processors
+                    self.directives.push(construct_bsp_vmsa(
+                        vmsa_gpa,
+                        *compatibility_mask,
+                        *platform,
+                        &self.vmsa_options(),
+                        &BspOptions {
+                            reset_addr: self.bsp_reset_addr(),
+                            registers: &self.options.bsp_reg,
+                            rsp: self.options.bsp_rsp.unwrap_or(0),
+                            gdtr: self.options.gdtr,
+                            idtr: self.options.idtr,
+                            long_mode_cr3: self.options.long_mode_bsp,
+                        },
+                    )?);
+                    self.directives.append(&mut construct_ap_vmsas(
+                        vmsa_gpa,
+                        *compatibility_mask,
+                        *platform,
+                        &self.vmsa_options(),
+                        self.firmware.fw_info(),
+                        self.options.cpucount,
+                        self.options.ap_reset,
                     )?);
+                    vmsas += self.options.cpucount as usize;
+                    self.print_progress(&format!("Generated {vmsas}/{total_vmsas} VMSAs"));
                 }
+                // A raw payload has no reset vector of its own so the loader
+                // is told where the BSP starts executing.
+                cmd_options::Platform::Native if self.options.raw_payload.is_some() => {
+                    self.directives.push(construct_native_bsp_context(
+                        *compatibility_mask,
+                        self.bsp_reset_addr(),
+                        self.options.bsp_rsp.unwrap_or(0),
+                    ));
+                }
+                // The initial register state of a TDX guest is defined by the
+                // TDX module and cannot be provided in the IGVM file. The BSP
+                // always starts at the architectural reset vector.
+                cmd_options::Platform::Tdx => (),
+                _ => (),
+            }
+            if let (cmd_options::Platform::SevSnp, Some(id_block)) = (platform, &self.id_block) {
+                self.directives
+                    .push(id_block.directive(*compatibility_mask));
             }
-            _ => (),
         }
         Ok(())
     }
 
-    fn build_initialization(&mut self) -> Result<(), Box<dyn Error>> {
-        let policy = match self.options.platform {
-            cmd_options::Platform::Sev => 1,             // No Debug
-            cmd_options::Platform::SevEs => 5,           // No Debug and ES required
-            cmd_options::Platform::SevSnp => 0x30000u64, // Reserved bit set and SMT allowed
-            cmd_options::Platform::Native => 0,
-        };
-        self.initialization
-            .push(IgvmInitializationHeader::GuestPolicy {
-                policy,
-                compatibility_mask: COMPATIBILITY_MASK,
+    fn build_parameters(&mut self) -> Result<(), Box<dyn Error>> {
+        if let Some(cmdline) = &self.options.cmdline {
+            // The command line is provided as a null terminated string in a
+            // single page parameter area.
+            let mut initial_data = cmdline.as_bytes().to_vec();
+            initial_data.push(0);
+            if initial_data.len() > PAGE_SIZE_4K as usize {
+                return Err(format!(
+                    "The command line is {} bytes but must be less than {PAGE_SIZE_4K} bytes",
+                    cmdline.len()
+                )
+                .into());
+            }
+            if self.options.cmdline_gpa & (PAGE_SIZE_4K - 1) != 0 {
+                return Err(format!(
+                    "The command line GPA {:#X} is not page aligned",
+                    self.options.cmdline_gpa
+                )
+                .into());
+            }
+            let parameter_area_index = self.next_parameter_area_index();
+            self.directives.push(IgvmDirectiveHeader::ParameterArea {
+                number_of_bytes: PAGE_SIZE_4K,
+                parameter_area_index,
+                initial_data,
             });
+            self.directives
+                .push(IgvmDirectiveHeader::CommandLine(IGVM_VHS_PARAMETER {
+                    parameter_area_index,
+                    byte_offset: 0,
+                }));
+            self.directives.push(IgvmDirectiveHeader::ParameterInsert(
+                IGVM_VHS_PARAMETER_INSERT {
+                    gpa: self.options.cmdline_gpa,
+                    compatibility_mask: Self::all_platforms_mask(&self.compatibility_masks),
+                    parameter_area_index,
+                },
+            ));
+        }
+
+        if let Some(memory_map_gpa) = self.options.memory_map_gpa {
+            self.build_memory_map(memory_map_gpa)?;
+        }
+
+        if let Some(vp_count_gpa) = self.options.vp_count_gpa {
+            self.build_vp_count(vp_count_gpa)?;
+        }
+
+        if let (Some(acpi_gpa), Some(tables)) = (self.options.acpi_gpa, &self.acpi_tables) {
+            // The tables are provided in a parameter area so the loader can
+            // insert them without them being part of the firmware image.
+            let number_of_bytes = (tables.len() as u64).next_multiple_of(PAGE_SIZE_4K);
+            let fw_info = self.firmware.fw_info();
+            let fw_range = fw_info.start as u64..fw_info.start as u64 + fw_info.size as u64;
+            if overlaps(&(acpi_gpa..acpi_gpa + number_of_bytes), &fw_range) {
+                return Err(format!(
+                    "The ACPI tables at {:#X}-{:#X} overlap the firmware at {:#X}-{:#X}",
+                    acpi_gpa,
+                    acpi_gpa + number_of_bytes,
+                    fw_range.start,
+                    fw_range.end
+                )
+                .into());
+            }
+            let parameter_area_index = self.next_parameter_area_index();
+            self.directives.push(IgvmDirectiveHeader::ParameterArea {
+                number_of_bytes,
+                parameter_area_index,
+                initial_data: tables.clone(),
+            });
+            self.directives.push(IgvmDirectiveHeader::ParameterInsert(
+                IGVM_VHS_PARAMETER_INSERT {
+                    gpa: acpi_gpa,
+                    compatibility_mask: Self::all_platforms_mask(&self.compatibility_masks),
+                    parameter_area_index,
+                },
+            ));
+        }
+        if let Some(description) = &self.options.description {
+            self.directives.push(description_area(
+                description,
+                self.next_parameter_area_index(),
+            ));
+        }
+        Ok(())
+    }
+
+    /// Describe the number of virtual processors in a parameter area so it is
+    /// available to the guest on platforms that do not have a VMSA for each
+    /// processor. The loader replaces the initial value with the number of
+    /// processors it starts.
+    fn build_vp_count(&mut self, gpa: u64) -> Result<(), Box<dyn Error>> {
+        if gpa & (PAGE_SIZE_4K - 1) != 0 {
+            return Err(format!("The VP count GPA {gpa:#X} is not page aligned").into());
+        }
+        let parameter_area_index = self.next_parameter_area_index();
+        self.directives.push(IgvmDirectiveHeader::ParameterArea {
+            number_of_bytes: PAGE_SIZE_4K,
+            parameter_area_index,
+            initial_data: (self.options.cpucount as u32).to_le_bytes().to_vec(),
+        });
+        self.directives
+            .push(IgvmDirectiveHeader::VpCount(IGVM_VHS_PARAMETER {
+                parameter_area_index,
+                byte_offset: 0,
+            }));
+        self.directives.push(IgvmDirectiveHeader::ParameterInsert(
+            IGVM_VHS_PARAMETER_INSERT {
+                gpa,
+                compatibility_mask: Self::all_platforms_mask(&self.compatibility_masks),
+                parameter_area_index,
+            },
+        ));
+        Ok(())
+    }
+
+    /// Describe the regions that the firmware requests to be prevalidated in
+    /// a memory map parameter area so they can be discovered by the guest. The
+    /// list is terminated by an entry with zero pages.
+    fn build_memory_map(&mut self, gpa: u64) -> Result<(), Box<dyn Error>> {
+        if gpa & (PAGE_SIZE_4K - 1) != 0 {
+            return Err(format!("The memory map GPA {gpa:#X} is not page aligned").into());
+        }
+        // Prevalidated memory is only populated for SEV-SNP.
+        let compatibility_mask = self
+            .compatibility_masks
+            .iter()
+            .filter(|(platform, _)| *platform == Platform::SevSnp)
+            .fold(0, |mask, (_, platform_mask)| mask | platform_mask);
+        if compatibility_mask == 0 {
+            return Err("A memory map can only be used with the SEV-SNP platform".into());
+        }
+
+        let fw_info = self.firmware.fw_info();
+        let mut initial_data = Vec::<u8>::new();
+        for pv_mem in &fw_info.prevalidated {
+            let entry = IGVM_VHS_MEMORY_MAP_ENTRY {
+                starting_gpa_page_number: pv_mem.base as u64 / PAGE_SIZE_4K,
+                number_of_pages: (pv_mem.size as u64).div_ceil(PAGE_SIZE_4K),
+                entry_type: MemoryMapEntryType::MEMORY,
+                flags: 0,
+                reserved: 0,
+            };
+            initial_data.extend_from_slice(entry.as_bytes());
+        }
+        // The area is a single page, which must also hold the terminating
+        // entry.
+        let entry_size = size_of::<IGVM_VHS_MEMORY_MAP_ENTRY>();
+        if initial_data.len() > PAGE_SIZE_4K as usize - entry_size {
+            return Err(format!(
+                "The {} prevalidated regions do not fit in the memory map, which holds at most {} regions",
+                fw_info.prevalidated.len(),
+                PAGE_SIZE_4K as usize / entry_size - 1
+            )
+            .into());
+        }
+
+        let parameter_area_index = self.next_parameter_area_index();
+        self.directives.push(IgvmDirectiveHeader::ParameterArea {
+            number_of_bytes: PAGE_SIZE_4K,
+            parameter_area_index,
+            initial_data,
+        });
+        self.directives.push(IgvmDirectiveHeader::ParameterInsert(
+            IGVM_VHS_PARAMETER_INSERT {
+                gpa,
+                compatibility_mask,
+                parameter_area_index,
+            },
+        ));
+        Ok(())
+    }
+
+    fn next_parameter_area_index(&self) -> u32 {
+        self.directives
+            .iter()
+            .filter(|directive| matches!(directive, IgvmDirectiveHeader::ParameterArea { .. }))
+            .count() as u32
+    }
+
+    /// The size in bytes of the required guest RAM region, which starts at GPA
+    /// zero and must end below the firmware.
+    fn required_memory_size(&self) -> Result<Option<u32>, Box<dyn Error>> {
+        let Some(memory_size_mb) = self.options.memory_size else {
+            return Ok(None);
+        };
+        let fw_start = self.firmware.fw_info().start as u64;
+        let memory_size = memory_size_mb as u64 * 1024 * 1024;
+        if memory_size > fw_start {
+            return Err(format!(
+                "The required memory size {memory_size_mb}MB overlaps the firmware at {fw_start:#X}"
+            )
+            .into());
+        }
+        Ok(Some(memory_size as u32))
+    }
+
+    /// The regions of guest memory that are populated before the kernel is
+    /// placed: the firmware, including the SEV-SNP pages described by its
+    /// metadata, the additional firmware images, the VMSA page and the
+    /// parameter areas.
+    fn populated_regions(
+        options: &BuildOptions,
+        fw_info: &OvmfFwInfo,
+        firmware_images: &FirmwareImages,
+        acpi_tables: Option<&Vec<u8>>,
+    ) -> Vec<(String, Range<u64>)> {
+        let mut regions = if options.platform.contains(&Platform::SevSnp) {
+            fw_info.snp_regions()
+        } else {
+            vec![(
+                "firmware".to_string(),
+                fw_info.start as u64..fw_info.start as u64 + fw_info.size as u64,
+            )]
+        };
+        for (filename, range) in firmware_images.ranges() {
+            regions.push((format!("firmware image {filename}"), range.clone()));
+        }
+        let has_vmsa = options
+            .platform
+            .iter()
+            .any(|platform| matches!(platform, Platform::SevEs | Platform::SevSnp));
+        if has_vmsa && !options.no_vmsa {
+            regions.push((
+                "VMSA page".to_string(),
+                options.vmsa_gpa..options.vmsa_gpa.saturating_add(PAGE_SIZE_4K),
+            ));
+        }
+        // The parameter areas are inserted by the loader at these GPAs.
+        let page = |gpa: u64| gpa..gpa.saturating_add(PAGE_SIZE_4K);
+        if options.cmdline.is_some() {
+            regions.push(("command line".to_string(), page(options.cmdline_gpa)));
+        }
+        if let Some(gpa) = options.memory_map_gpa {
+            regions.push(("memory map".to_string(), page(gpa)));
+        }
+        if let Some(gpa) = options.vp_count_gpa {
+            regions.push(("VP count".to_string(), page(gpa)));
+        }
+        if let (Some(gpa), Some(tables)) = (options.acpi_gpa, acpi_tables) {
+            let number_of_bytes = (tables.len() as u64).next_multiple_of(PAGE_SIZE_4K);
+            regions.push((
+                "ACPI tables".to_string(),
+                gpa..gpa.saturating_add(number_of_bytes),
+            ));
+        }
+        regions
+    }
+
+    /// The VMSA pages are not visible to the guest so the GPA only needs to be
+    /// page aligned. The default is the highest page below 2^48, outside of
+    /// guest RAM and the firmware that ends at 4GB, so it cannot collide with
+    /// a populated page.
+    fn vmsa_gpa(&self) -> Result<u64, Box<dyn Error>> {
+        let vmsa_gpa = self.options.vmsa_gpa;
+        if vmsa_gpa & (PAGE_SIZE_4K - 1) != 0 {
+            return Err(format!("The VMSA GPA {vmsa_gpa:#X} is not page aligned").into());
+        }
+        Ok(vmsa_gpa)
+    }
+
+    /// The SEV firmware converts the page to a VMSA page when the VMSA is
+    /// added so it must not also be populated for an SEV-ES or SEV-SNP
+    /// platform. This is checked once all directives, including the
+    /// parameter areas, have been built.
+    fn check_vmsa_gpa(&self) -> Result<(), Box<dyn Error>> {
+        if self.options.no_vmsa {
+            return Ok(());
+        }
+        let sev_mask = self
+            .compatibility_masks
+            .iter()
+            .filter(|(platform, _)| matches!(platform, Platform::SevEs | Platform::SevSnp))
+            .fold(0, |mask, (_, platform_mask)| mask | platform_mask);
+        if sev_mask == 0 {
+            return Ok(());
+        }
+        let vmsa_gpa = self.options.vmsa_gpa;
+        let vmsa_range = vmsa_gpa..vmsa_gpa.saturating_add(PAGE_SIZE_4K);
+        let collision = populated_ranges(&self.directives, sev_mask)
+            .into_iter()
+            .find(|range| overlaps(range, &vmsa_range));
+        if let Some(range) = collision {
+            let fw_info = self.firmware.fw_info();
+            let fw_start = fw_info.start as u64;
+            let location = if vmsa_gpa >= fw_start && vmsa_gpa < fw_start + fw_info.size as u64 {
+                "the firmware".to_string()
+            } else {
+                format!("the populated range {:#X}-{:#X}", range.start, range.end)
+            };
+            return Err(format!(
+                "The VMSA GPA {vmsa_gpa:#X} overlaps {location}. Use --vmsa-gpa to choose an unused address"
+            )
+            .into());
+        }
+        // A VMSA page within the guest RAM cannot be used by the guest as
+        // memory once the VMSA has been added.
+        if let Some(memory_size) = self.required_memory_size()? {
+            if vmsa_gpa < memory_size as u64 {
+                self.options.warn(&format!(
+                    "The VMSA GPA {vmsa_gpa:#X} is within the required guest memory below {memory_size:#X}"
+                ));
+            }
+        }
+        Ok(())
+    }
+
+    /// The BSP reset address can be overridden on the command line. Otherwise
+    /// the BSP starts at the architectural reset vector. The reset address in
+    /// the firmware SEV info block is only used by the APs.
+    fn bsp_reset_addr(&self) -> u32 {
+        match self.options.bsp_reset {
+            Some(reset_addr) => reset_addr,
+            // A raw payload is entered at its first byte.
+            None if self.options.raw_payload.is_some() => self.firmware.fw_info().start,
+            None => DEFAULT_BSP_RESET_ADDR,
+        }
+    }
+
+    fn build_initialization(&mut self) -> Result<(), Box<dyn Error>> {
+        for (platform, compatibility_mask) in &self.compatibility_masks {
+            let default_policy = match platform {
+                cmd_options::Platform::Sev => 1,             // No Debug
+                cmd_options::Platform::SevEs => 5,           // No Debug and ES required
+                cmd_options::Platform::SevSnp => 0x30000u64, // Reserved bit set and SMT allowed
+                cmd_options::Platform::Native => 0,
+                // TDX does not use a guest policy.
+                cmd_options::Platform::Tdx => continue,
+            };
+            let policy = match (platform, self.options.policy) {
+                (cmd_options::Platform::Native, _) | (_, None) => default_policy,
+                (_, Some(policy)) => {
+                    Self::validate_policy(*platform, policy)?;
+                    policy
+                }
+            };
+            if let (cmd_options::Platform::SevSnp, Some(id_block)) = (platform, &self.id_block) {
+                if id_block.policy() != policy {
+                    return Err(format!(
+                        "The ID block policy {:#x} does not match the guest policy {policy:#x}",
+                        id_block.policy()
+                    )
+                    .into());
+                }
+            }
+            self.initialization
+                .push(IgvmInitializationHeader::GuestPolicy {
+                    policy,
+                    compatibility_mask: *compatibility_mask,
+                });
+        }
         Ok(())
     }
 
-    fn filter_pages(directive: &IgvmDirectiveHeader) -> bool {
-        matches!(directive, IgvmDirectiveHeader::PageData { .. })
-            || matches!(directive, IgvmDirectiveHeader::SnpVpContext { .. })
+    fn validate_policy(platform: Platform, policy: u64) -> Result<(), Box<dyn Error>> {
+        match platform {
+            cmd_options::Platform::Sev if policy & SEV_POLICY_ES != 0 => Err(format!(
+                "Guest policy {policy:#x} requires SEV-ES which is not supported on SEV"
+            )
+            .into()),
+            cmd_options::Platform::SevEs if policy & SEV_POLICY_ES == 0 => Err(format!(
+                "Guest policy {policy:#x} must have the ES bit (2) set for SEV-ES"
+            )
+            .into()),
+            cmd_options::Platform::SevSnp if policy & SNP_POLICY_RESERVED_MBO == 0 => Err(format!(
+                "Guest policy {policy:#x} must have the reserved bit (17) set for SEV-SNP"
+            )
+            .into()),
+            _ => Ok(()),
+        }
+    }
+
+    fn directive_order(directive: &IgvmDirectiveHeader, sort_pages: bool) -> (u8, u64) {
+        match directive {
+            IgvmDirectiveHeader::PageData { gpa, .. } if sort_pages => (1, *gpa),
+            IgvmDirectiveHeader::PageData { .. } => (1, 0),
+            IgvmDirectiveHeader::SnpVpContext { vp_index, .. } => (2, *vp_index as u64),
+            _ => (0, 0),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use clap::Parser;
+
+    use crate::cmd_options::{CmdOptions, Command};
+    use crate::common::{TestImage, TEST_RESET_ADDR};
+
+    /// Build the headers for the synthetic test firmware with the given build
+    /// arguments.
+    fn build(name: &str, args: &[&str]) -> Result<IgvmBuilder, Box<dyn Error>> {
+        build_image(name, &TestImage::default(), args)
+    }
+
+    /// Build the headers for a synthetic firmware image. The firmware is
+    /// written to a temporary directory that is removed once it has been read.
+    fn build_image(
+        name: &str,
+        image: &TestImage,
+        args: &[&str],
+    ) -> Result<IgvmBuilder, Box<dyn Error>> {
+        let dir = std::env::temp_dir().join(format!("buildigvm-{}-{name}", std::process::id()));
+        fs::create_dir_all(&dir)?;
+        let firmware = dir.join("OVMF.fd");
+        fs::write(&firmware, image.build())?;
+        let output = dir.join("out.igvm");
+        let mut argv = vec![
+            "buildigvm",
+            "build",
+            "--firmware",
+            firmware.to_str().unwrap(),
+            "--output",
+            output.to_str().unwrap(),
+        ];
+        argv.extend_from_slice(args);
+        let Command::Build(options) = CmdOptions::try_parse_from(argv)?.command else {
+            unreachable!()
+        };
+        let builder = IgvmBuilder::new(*options);
+        fs::remove_dir_all(&dir)?;
+        let mut builder = builder?;
+        builder.build()?;
+        Ok(builder)
+    }
+
+    #[test]
+    fn write_output_leaves_other_files_alone() {
+        let dir = std::env::temp_dir().join(format!("buildigvm-{}-write", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let output = dir.join("out.igvm");
+        let output = output.to_str().unwrap();
+        fs::write(format!("{output}.tmp"), b"unrelated").unwrap();
+        IgvmBuilder::write_output(output, b"igvm", None).unwrap();
+        assert_eq!(fs::read(output).unwrap(), b"igvm");
+        assert_eq!(fs::read(format!("{output}.tmp")).unwrap(), b"unrelated");
+        assert_eq!(fs::read_dir(&dir).unwrap().count(), 2);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn build_moves_the_page_directives() {
+        let mut builder = build("once", &["-p", "native"]).unwrap();
+        assert!(builder
+            .directives()
+            .iter()
+            .any(|directive| matches!(directive, IgvmDirectiveHeader::PageData { .. })));
+        assert!(builder.build().is_err());
+    }
+
+    #[test]
+    fn pages_follow_other_directives() {
+        let builder = build(
+            "order",
+            &[
+                "-p",
+                "sev-snp",
+                "--cpucount",
+                "2",
+                "--cmdline",
+                "console=ttyS0",
+            ],
+        )
+        .unwrap();
+        let classes: Vec<u8> = builder
+            .directives()
+            .iter()
+            .map(|directive| IgvmBuilder::directive_order(directive, false).0)
+            .collect();
+        assert!(classes.windows(2).all(|pair| pair[0] <= pair[1]));
+        assert!(classes.contains(&0));
+        assert_eq!(classes.iter().filter(|class| **class == 2).count(), 2);
+
+        // The sort is stable so the firmware pages stay in file order.
+        let firmware_gpas: Vec<u64> = builder
+            .directives()
+            .iter()
+            .filter_map(|directive| match directive {
+                IgvmDirectiveHeader::PageData { gpa, .. } if *gpa >= 0xfffe0000 => Some(*gpa),
+                _ => None,
+            })
+            .collect();
+        assert_eq!(firmware_gpas.len(), 0x20);
+        assert!(firmware_gpas.windows(2).all(|pair| pair[0] < pair[1]));
+    }
+
+    fn vp_indices(builder: &IgvmBuilder) -> Vec<u16> {
+        builder
+            .directives()
+            .iter()
+            .filter_map(|directive| match directive {
+                IgvmDirectiveHeader::SnpVpContext { vp_index, .. } => Some(*vp_index),
+                _ => None,
+            })
+            .collect()
+    }
+
+    #[test]
+    fn vp_contexts_in_vp_index_order() {
+        let builder = build("vp-order", &["-p", "sev-snp", "--cpucount", "4"]).unwrap();
+        assert_eq!(vp_indices(&builder), [0, 1, 2, 3]);
+        assert!(matches!(
+            builder.directives().last(),
+            Some(IgvmDirectiveHeader::SnpVpContext { vp_index: 3, .. })
+        ));
+
+        let builder = build(
+            "vp-order-multi",
+            &["-p", "sev-es", "-p", "sev-snp", "--cpucount", "3"],
+        )
+        .unwrap();
+        assert_eq!(vp_indices(&builder), [0, 0, 1, 1, 2, 2]);
+    }
+
+    fn page_gpas(builder: &IgvmBuilder) -> Vec<u64> {
+        builder
+            .directives()
+            .iter()
+            .filter_map(|directive| match directive {
+                IgvmDirectiveHeader::PageData { gpa, .. } => Some(*gpa),
+                _ => None,
+            })
+            .collect()
+    }
+
+    #[test]
+    fn sort_pages_by_gpa() {
+        let args = ["-p", "sev-snp", "--cpucount", "2"];
+        let builder = build("unsorted", &args).unwrap();
+        let gpas = page_gpas(&builder);
+        assert!(!gpas.windows(2).all(|pair| pair[0] < pair[1]));
+
+        let builder = build("sorted", &[&args[..], &["--sort-pages"]].concat()).unwrap();
+        let sorted = page_gpas(&builder);
+        assert!(sorted.windows(2).all(|pair| pair[0] < pair[1]));
+        assert_eq!(sorted.len(), gpas.len());
+        assert_eq!(vp_indices(&builder), [0, 1]);
+        assert!(matches!(
+            builder.directives().last(),
+            Some(IgvmDirectiveHeader::SnpVpContext { .. })
+        ));
+    }
+
+    #[test]
+    fn ap_reset_required_without_reset_block() {
+        let image = TestImage {
+            reset_addr: None,
+            ..Default::default()
+        };
+        let err = build_image(
+            "no-reset-block",
+            &image,
+            &["-p", "sev-snp", "--cpucount", "2"],
+        )
+        .err()
+        .unwrap()
+        .to_string();
+        assert!(err.contains("Use --ap-reset"), "{err}");
+
+        let builder = build_image("no-reset-block-bsp", &image, &["-p", "sev-snp"]).unwrap();
+        assert_eq!(vp_indices(&builder), [0]);
+
+        let builder = build_image(
+            "no-reset-block-ap-reset",
+            &image,
+            &["-p", "sev-snp", "--cpucount", "2", "--ap-reset", "ffff1000"],
+        )
+        .unwrap();
+        let ap_rip = builder
+            .directives()
+            .iter()
+            .find_map(|directive| match directive {
+                IgvmDirectiveHeader::SnpVpContext {
+                    vp_index: 1, vmsa, ..
+                } => Some((vmsa.cs.base, vmsa.rip)),
+                _ => None,
+            });
+        assert_eq!(ap_rip, Some((0xffff0000, 0x1000)));
+    }
+
+    #[test]
+    fn kernel_must_not_overlap_parameter_areas() {
+        let kernel = std::env::temp_dir().join(format!(
+            "buildigvm-{}-kernel-overlap.bin",
+            std::process::id()
+        ));
+        fs::write(&kernel, [0x5a; 0x3000]).unwrap();
+        let kernel_args = ["--kernel", kernel.to_str().unwrap(), "--kernel-gpa"];
+        let build_kernel = |name, gpa, args: &[&str]| {
+            build(
+                name,
+                &[&kernel_args[..], &[gpa, "-p", "native"], args].concat(),
+            )
+        };
+
+        // The kernel ends just below the command line page at 0x20000.
+        assert!(build_kernel("kernel-below-cmdline", "1d000", &["--cmdline", "quiet"]).is_ok());
+        let err = build_kernel("kernel-over-cmdline", "1e000", &["--cmdline", "quiet"])
+            .err()
+            .unwrap()
+            .to_string();
+        assert!(err.contains("overlaps the command line"), "{err}");
+        let err = build_kernel(
+            "kernel-over-vp-count",
+            "1e000",
+            &["--vp-count-gpa", "20000"],
+        )
+        .err()
+        .unwrap()
+        .to_string();
+        assert!(err.contains("overlaps the VP count"), "{err}");
+        fs::remove_file(&kernel).unwrap();
+    }
+
+    #[test]
+    fn bsp_starts_at_reset_vector() {
+        let bsp_rip = |builder: &IgvmBuilder| {
+            builder
+                .directives()
+                .iter()
+                .find_map(|directive| match directive {
+                    IgvmDirectiveHeader::SnpVpContext {
+                        vp_index: 0, vmsa, ..
+                    } => Some((vmsa.cs.base, vmsa.rip)),
+                    _ => None,
+                })
+        };
+
+        // The SEV info block of the test firmware gives a different reset
+        // address, which is only used by the APs.
+        assert_ne!(TEST_RESET_ADDR, DEFAULT_BSP_RESET_ADDR);
+        let builder = build("bsp-reset-default", &["-p", "sev-snp"]).unwrap();
+        assert_eq!(bsp_rip(&builder), Some((0xffff0000, 0xfff0)));
+
+        let builder = build(
+            "bsp-reset-override",
+            &["-p", "sev-snp", "--bsp-reset", "ffff1000"],
+        )
+        .unwrap();
+        assert_eq!(bsp_rip(&builder), Some((0xffff0000, 0x1000)));
+    }
+
+    #[test]
+    fn snp_feature_only_on_snp_vmsa() {
+        let builder = build(
+            "sev-es-and-snp",
+            &["-p", "sev-es", "-p", "sev-snp", "--cpucount", "2"],
+        )
+        .unwrap();
+        let mask = |platform| {
+            builder
+                .compatibility_masks
+                .iter()
+                .find(|(p, _)| *p == platform)
+                .unwrap()
+                .1
+        };
+        let (sev_es_mask, snp_mask) = (mask(Platform::SevEs), mask(Platform::SevSnp));
+        assert_ne!(sev_es_mask, snp_mask);
+
+        let mut vmsas = 0;
+        for directive in builder.directives() {
+            if let IgvmDirectiveHeader::SnpVpContext {
+                compatibility_mask,
+                vmsa,
+                ..
+            } = directive
+            {
+                assert!(*compatibility_mask == sev_es_mask || *compatibility_mask == snp_mask);
+                assert_eq!(vmsa.sev_features.snp(), *compatibility_mask == snp_mask);
+                vmsas += 1;
+            }
+        }
+        assert_eq!(vmsas, 4);
+    }
+
+    #[test]
+    fn memory_map_region_limit() {
+        let max_regions = PAGE_SIZE_4K as usize / size_of::<IGVM_VHS_MEMORY_MAP_ENTRY>() - 1;
+        let with_regions = |name, regions: usize| {
+            // The firmware already defines two prevalidated regions.
+            let prevalidate: Vec<String> = (0..regions - 2)
+                .map(|i| format!("{:x}:1000", 0x1000000 + i * 0x1000))
+                .collect();
+            let mut args = vec!["-p", "sev-snp", "--memory-map-gpa", "100000"];
+            for region in &prevalidate {
+                args.extend(["--prevalidate", region.as_str()]);
+            }
+            build(name, &args)
+        };
+        assert!(with_regions("memory-map-full", max_regions).is_ok());
+        let err = with_regions("memory-map-overflow", max_regions + 1)
+            .err()
+            .unwrap()
+            .to_string();
+        assert!(err.contains("do not fit in the memory map"), "{err}");
     }
 }
@@ -0,0 +1,69 @@
+// SPDX-License-Identifier: MIT OR Apache-2.0
+//
+// Copyright (c) 2024 SUSE LLC
+//
+// Author: Roy Hopkins <roy.hopkins@suse.com>
+
+use std::error::Error;
+use std::ops::Range;
+
+use igvm::IgvmDirectiveHeader;
+
+use crate::page_data::{build_page_data, load_image, overlaps};
+
+pub struct Kernel {
+    directives: Vec<IgvmDirectiveHeader>,
+}
+
+impl Kernel {
+    /// Load the kernel and optional initrd, which must not overlap each other
+    /// or any of the `reserved` ranges that are already populated.
+    pub fn parse(
+        kernel: &String,
+        kernel_gpa: u64,
+        initrd: Option<&String>,
+        initrd_gpa: u64,
+        compatibility_mask: u32,
+        reserved: &[(String, Range<u64>)],
+    ) -> Result<Self, Box<dyn Error>> {
+        let (kernel_data, kernel_range) = load_image(kernel, kernel_gpa, "kernel")?;
+        if let Some((name, range)) = reserved.iter().find(|(_, r)| overlaps(&kernel_range, r)) {
+            return Err(format!(
+                "The kernel at {:#X}-{:#X} overlaps the {name} at {:#X}-{:#X}",
+                kernel_range.start, kernel_range.end, range.start, range.end
+            )
+            .into());
+        }
+        let mut directives = build_page_data(&kernel_data, kernel_gpa, compatibility_mask);
+
+        if let Some(initrd) = initrd {
+            let (initrd_data, initrd_range) = load_image(initrd, initrd_gpa, "initrd")?;
+            if let Some((name, range)) = reserved.iter().find(|(_, r)| overlaps(&initrd_range, r)) {
+                return Err(format!(
+                    "The initrd at {:#X}-{:#X} overlaps the {name} at {:#X}-{:#X}",
+                    initrd_range.start, initrd_range.end, range.start, range.end
+                )
+                .into());
+            }
+            if overlaps(&initrd_range, &kernel_range) {
+                return Err(format!(
+                    "The initrd at {:#X}-{:#X} overlaps the kernel at {:#X}-{:#X}",
+                    initrd_range.start, initrd_range.end, kernel_range.start, kernel_range.end
+                )
+                .into());
+            }
+            directives.append(&mut build_page_data(
+                &initrd_data,
+                initrd_gpa,
+                compatibility_mask,
+            ));
+        }
+
+        Ok(Self { directives })
+    }
+
+    /// Hand the directives over to the caller, leaving none behind.
+    pub fn take_directives(&mut self) -> Vec<IgvmDirectiveHeader> {
+        std::mem::take(&mut self.directives)
+    }
+}
@@ -4,16 +4,41 @@
 //
 // Author: Roy Hopkins <rhopkins@suse.de>
 
+use clap::Parser;
+use cmd_options::{CmdOptions, Command};
 use igvm_builder::IgvmBuilder;
 use std::error::Error;
 
+mod acpi;
 mod cmd_options;
+#[cfg(test)]
+#[path = "../tests/common/mod.rs"]
+mod common;
+mod decompress;
+mod description;
+mod dump;
+mod firmware;
+mod firmware_image;
+mod hash;
+mod id_block;
 mod igvm_builder;
+mod kernel;
+mod manifest;
+mod measure;
 mod ovmf_firmware;
+mod page_data;
+mod raw_payload;
 mod vmsa;
 
 fn main() -> Result<(), Box<dyn Error>> {
-    let builder = IgvmBuilder::new()?;
-    builder.build()?;
+    match CmdOptions::parse().command {
+        Command::Build(options) => {
+            let mut builder = IgvmBuilder::new(*options)?;
+            builder.build()?;
+            builder.write()?;
+        }
+        Command::Dump(options) => dump::dump(&options)?,
+        Command::Platforms => cmd_options::list_platforms(),
+    }
     Ok(())
 }
@@ -0,0 +1,100 @@
+// SPDX-License-Identifier: MIT OR Apache-2.0
+//
+// Copyright (c) 2024 SUSE LLC
+//
+// Author: Roy Hopkins <roy.hopkins@suse.com>
+
+use std::error::Error;
+
+use igvm::IgvmInitializationHeader;
+
+use crate::cmd_options::{BuildOptions, Platform};
+use crate::firmware::FirmwareSource;
+
+#[cfg(feature = "json")]
+use {
+    crate::hash::{sha256, to_hex},
+    crate::ovmf_firmware::OvmfFwInfo,
+    clap::ValueEnum,
+    serde::Serialize,
+    std::collections::BTreeMap,
+};
+
+#[cfg(feature = "json")]
+#[derive(Serialize)]
+struct ManifestFirmware<'a> {
+    path: &'a str,
+    sha256: String,
+}
+
+#[cfg(feature = "json")]
+#[derive(Serialize)]
+struct Manifest<'a> {
+    firmware: ManifestFirmware<'a>,
+    platforms: Vec<String>,
+    cpucount: u16,
+    guest_policy: BTreeMap<String, u64>,
+    vmsa_gpa: u64,
+    firmware_info: &'a OvmfFwInfo,
+}
+
+#[cfg(feature = "json")]
+fn platform_name(platform: &Platform) -> String {
+    platform
+        .to_possible_value()
+        .map(|value| value.get_name().to_string())
+        .unwrap_or_else(|| format!("{platform:?}"))
+}
+
+/// Describe the inputs and configuration of a build as a JSON object so the
+/// build can be reproduced and checked by other tools. The guest policy of
+/// each platform is taken from the initialization headers.
+#[cfg(feature = "json")]
+pub fn build_manifest(
+    options: &BuildOptions,
+    firmware: &dyn FirmwareSource,
+    compatibility_masks: &[(Platform, u32)],
+    initialization: &[IgvmInitializationHeader],
+) -> Result<String, Box<dyn Error>> {
+    let guest_policy = compatibility_masks
+        .iter()
+        .filter_map(|(platform, mask)| {
+            initialization.iter().find_map(|header| match header {
+                IgvmInitializationHeader::GuestPolicy {
+                    policy,
+                    compatibility_mask,
+                } if compatibility_mask & mask != 0 => Some((platform_name(platform), *policy)),
+                _ => None,
+            })
+        })
+        .collect();
+    let manifest = Manifest {
+        firmware: ManifestFirmware {
+            path: options
+                .firmware
+                .as_ref()
+                .or(options.raw_payload.as_ref().map(|(_, filename)| filename))
+                .map_or("", |filename| filename),
+            sha256: to_hex(&sha256(firmware.data())),
+        },
+        platforms: compatibility_masks
+            .iter()
+            .map(|(platform, _)| platform_name(platform))
+            .collect(),
+        cpucount: options.cpucount,
+        guest_policy,
+        vmsa_gpa: options.vmsa_gpa,
+        firmware_info: firmware.fw_info(),
+    };
+    Ok(serde_json::to_string(&manifest)?)
+}
+
+#[cfg(not(feature = "json"))]
+pub fn build_manifest(
+    _options: &BuildOptions,
+    _firmware: &dyn FirmwareSource,
+    _compatibility_masks: &[(Platform, u32)],
+    _initialization: &[IgvmInitializationHeader],
+) -> Result<String, Box<dyn Error>> {
+    Err("JSON output is not supported: buildigvm was built without the json feature".into())
+}
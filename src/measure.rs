@@ -0,0 +1,221 @@
+// SPDX-License-Identifier: MIT OR Apache-2.0
+//
+// Copyright (c) 2024 SUSE LLC
+//
+// Author: Roy Hopkins <roy.hopkins@suse.com>
+
+use std::collections::HashMap;
+use std::error::Error;
+
+use igvm::IgvmDirectiveHeader;
+use igvm_defs::{IgvmPageDataType, PAGE_SIZE_4K};
+use sha2::{Digest, Sha384};
+use zerocopy::AsBytes;
+
+const SHA384_DIGEST_SIZE: usize = 48;
+
+// Page types used in the SEV-SNP PAGE_INFO structure.
+const SNP_PAGE_TYPE_NORMAL: u8 = 1;
+const SNP_PAGE_TYPE_VMSA: u8 = 2;
+const SNP_PAGE_TYPE_ZERO: u8 = 3;
+const SNP_PAGE_TYPE_UNMEASURED: u8 = 4;
+const SNP_PAGE_TYPE_SECRETS: u8 = 5;
+const SNP_PAGE_TYPE_CPUID: u8 = 6;
+
+// Size of the SEV-SNP PAGE_INFO structure.
+const PAGE_INFO_SIZE: usize = 0x70;
+
+/// Calculates the SEV-SNP launch digest by extending the current digest with
+/// the PAGE_INFO structure of each page in the order they are added to the
+/// guest.
+struct LaunchDigest {
+    digest: [u8; SHA384_DIGEST_SIZE],
+}
+
+impl LaunchDigest {
+    fn new() -> Self {
+        Self {
+            digest: [0; SHA384_DIGEST_SIZE],
+        }
+    }
+
+    fn update(&mut self, gpa: u64, page_type: u8, data: Option<&[u8]>) {
+        let mut page_info = [0u8; PAGE_INFO_SIZE];
+        page_info[0x00..0x30].copy_from_slice(&self.digest);
+        if let Some(data) = data {
+            // The contents are always measured as a full page.
+            let padding = [0u8; PAGE_SIZE_4K as usize];
+            let mut hasher = Sha384::new();
+            hasher.update(data);
+            hasher.update(&padding[data.len().min(padding.len())..]);
+            page_info[0x30..0x60].copy_from_slice(&hasher.finalize());
+        }
+        page_info[0x60..0x62].copy_from_slice(&(PAGE_INFO_SIZE as u16).to_le_bytes());
+        page_info[0x62] = page_type;
+        page_info[0x68..0x70].copy_from_slice(&gpa.to_le_bytes());
+        self.digest = Sha384::digest(page_info).into();
+    }
+
+    fn update_unmeasured(&mut self, gpa: u64, number_of_bytes: u64) {
+        for offset in (0..number_of_bytes).step_by(PAGE_SIZE_4K as usize) {
+            self.update(gpa + offset, SNP_PAGE_TYPE_UNMEASURED, None);
+        }
+    }
+}
+
+/// Calculate the SEV-SNP launch digest of the directives that apply to the
+/// given compatibility mask. Pages are measured in the order they appear in
+/// the directives and the VMSAs are measured last, once all pages have been
+/// added. Parameter areas are imported as unmeasured pages, so their contents
+/// do not affect the digest.
+pub fn snp_launch_digest(
+    directives: &[IgvmDirectiveHeader],
+    compatibility_mask: u32,
+) -> Result<[u8; SHA384_DIGEST_SIZE], Box<dyn Error>> {
+    let mut launch_digest = LaunchDigest::new();
+    let mut parameter_areas = HashMap::<u32, u64>::new();
+    let mut vmsas = Vec::new();
+
+    for directive in directives {
+        match directive {
+            IgvmDirectiveHeader::PageData {
+                gpa,
+                compatibility_mask: mask,
+                flags,
+                data_type,
+                data,
+            } if mask & compatibility_mask != 0 => {
+                if flags.is_2mb_page() {
+                    return Err(format!(
+                        "Cannot measure the 2MB page at {gpa:#X}: only 4K pages are supported"
+                    )
+                    .into());
+                }
+                if flags.shared() {
+                    // Shared pages are not part of the launch measurement.
+                    continue;
+                }
+                match *data_type {
+                    IgvmPageDataType::SECRETS => {
+                        launch_digest.update(*gpa, SNP_PAGE_TYPE_SECRETS, None)
+                    }
+                    IgvmPageDataType::CPUID_DATA | IgvmPageDataType::CPUID_XF => {
+                        launch_digest.update(*gpa, SNP_PAGE_TYPE_CPUID, None)
+                    }
+                    _ if flags.unmeasured() => {
+                        launch_digest.update(*gpa, SNP_PAGE_TYPE_UNMEASURED, None)
+                    }
+                    _ if data.is_empty() => launch_digest.update(*gpa, SNP_PAGE_TYPE_ZERO, None),
+                    _ => launch_digest.update(*gpa, SNP_PAGE_TYPE_NORMAL, Some(data)),
+                }
+            }
+            IgvmDirectiveHeader::ParameterArea {
+                number_of_bytes,
+                parameter_area_index,
+                ..
+            } => {
+                parameter_areas.insert(*parameter_area_index, *number_of_bytes);
+            }
+            IgvmDirectiveHeader::ParameterInsert(insert)
+                if insert.compatibility_mask & compatibility_mask != 0 =>
+            {
+                let number_of_bytes =
+                    parameter_areas
+                        .get(&insert.parameter_area_index)
+                        .ok_or(format!(
+                            "Parameter area {} is inserted before it is declared",
+                            insert.parameter_area_index
+                        ))?;
+                launch_digest.update_unmeasured(insert.gpa, *number_of_bytes);
+            }
+            IgvmDirectiveHeader::SnpVpContext {
+                gpa,
+                compatibility_mask: mask,
+                vmsa,
+                ..
+            } if mask & compatibility_mask != 0 => vmsas.push((*gpa, vmsa)),
+            _ => {}
+        }
+    }
+
+    for (gpa, vmsa) in vmsas {
+        launch_digest.update(gpa, SNP_PAGE_TYPE_VMSA, Some(vmsa.as_bytes()));
+    }
+    Ok(launch_digest.digest)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::hash::to_hex;
+    use igvm_defs::{IgvmPageDataFlags, IGVM_VHS_PARAMETER_INSERT};
+
+    fn page(gpa: u64, data_type: IgvmPageDataType, data: &[u8]) -> IgvmDirectiveHeader {
+        IgvmDirectiveHeader::PageData {
+            gpa,
+            compatibility_mask: 1,
+            flags: IgvmPageDataFlags::new(),
+            data_type,
+            data: data.to_vec(),
+        }
+    }
+
+    #[test]
+    fn launch_digest_matches_reference() {
+        let directives = [
+            page(0x1000, IgvmPageDataType::NORMAL, b"abc"),
+            page(0x2000, IgvmPageDataType::NORMAL, &[]),
+            page(0x3000, IgvmPageDataType::SECRETS, &[]),
+            // Pages for other platforms are not measured.
+            IgvmDirectiveHeader::PageData {
+                gpa: 0x4000,
+                compatibility_mask: 2,
+                flags: IgvmPageDataFlags::new(),
+                data_type: IgvmPageDataType::NORMAL,
+                data: vec![1],
+            },
+        ];
+        let digest = snp_launch_digest(&directives, 1).unwrap();
+        assert_eq!(
+            to_hex(&digest),
+            "9249bdf62721b19e8ac20ecb42b47bf1779198797415d4d398f4871f73ed16e1\
+             09406c8979177678f94331807dc38223"
+        );
+    }
+
+    #[test]
+    fn parameter_inserts_are_unmeasured() {
+        let directives = [
+            page(0x1000, IgvmPageDataType::NORMAL, b"abc"),
+            // The initial data of a parameter area is not measured.
+            IgvmDirectiveHeader::ParameterArea {
+                number_of_bytes: 0x2000,
+                parameter_area_index: 0,
+                initial_data: b"console=ttyS0".to_vec(),
+            },
+            IgvmDirectiveHeader::ParameterInsert(IGVM_VHS_PARAMETER_INSERT {
+                gpa: 0x20000,
+                compatibility_mask: 1,
+                parameter_area_index: 0,
+            }),
+        ];
+        let digest = snp_launch_digest(&directives, 1).unwrap();
+        assert_eq!(
+            to_hex(&digest),
+            "9fbf695aa16d0eaf9a18e638d9473bcb7a5b33d43fc95e1d406fe724dcee992c\
+             f0e245fb40688229b0637551ffe673bb"
+        );
+    }
+
+    #[test]
+    fn large_pages_are_rejected() {
+        let directives = [IgvmDirectiveHeader::PageData {
+            gpa: 0x200000,
+            compatibility_mask: 1,
+            flags: IgvmPageDataFlags::new().with_is_2mb_page(true),
+            data_type: IgvmPageDataType::NORMAL,
+            data: vec![],
+        }];
+        assert!(snp_launch_digest(&directives, 1).is_err());
+    }
+}
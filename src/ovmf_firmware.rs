@@ -8,28 +8,57 @@ use std::error::Error;
 use std::fs::File;
 use std::io::Read;
 use std::mem::size_of;
+use std::ops::Range;
 
 use igvm::IgvmDirectiveHeader;
 use igvm_defs::{IgvmPageDataFlags, IgvmPageDataType, PAGE_SIZE_4K};
 use uuid::{uuid, Uuid};
 
 use crate::cmd_options::Platform;
+use crate::decompress::decompress;
+use crate::firmware::FirmwareSource;
+use crate::hash::{sha256, to_hex, SHA256_DIGEST_SIZE};
+use crate::page_data::build_page_data;
 
 const OVMF_TABLE_FOOTER_GUID: Uuid = uuid!("96b582de-1fb2-45f7-baea-a366c55a082d");
 const OVMF_SEV_METADATA_GUID: Uuid = uuid!("dc886566-984a-4798-a75e-5585a7bf67cc");
+// The SEV info block is the SEV-ES reset block in the OVMF reset vector, which
+// contains the address at which APs start executing. There is no separate AP
+// reset block GUID.
 const SEV_INFO_BLOCK_GUID: Uuid = uuid!("00f771de-1a7e-4fcb-890e-68c77e2fb44e");
 
 const SEV_META_DESC_TYPE_MEM: u32 = 1;
 const SEV_META_DESC_TYPE_SECRETS: u32 = 2;
 const SEV_META_DESC_TYPE_CPUID: u32 = 3;
 const SEV_META_DESC_TYPE_CAA: u32 = 4;
+// Region reserved for the hashes of the kernel, initrd and command line used
+// by a measured direct boot. It is populated with zero pages at launch.
+const SEV_META_DESC_TYPE_KERNEL_HASHES: u32 = 0x10;
+
+// The reset vector occupies the last 16 bytes of the firmware, ending at 4GB.
+const RESET_VECTOR_SIZE: usize = 16;
+const OPCODE_NOP: u8 = 0x90;
+const OPCODE_JMP_NEAR: u8 = 0xe9;
+const OPCODE_JMP_FAR: u8 = 0xea;
+const OPCODE_JMP_SHORT: u8 = 0xeb;
+
+// The largest firmware that can be mapped below 4GB with a 32-bit start
+// address and a size that is a multiple of the page size.
+const MAX_FIRMWARE_SIZE: usize = 0xfffff000;
+
+const PAGE_SIZE_2M: u64 = 0x200000;
 
 // Offset from the end of the file where the OVMF table footer GUID should be.
 const FOOTER_OFFSET: usize = 32;
 
+// How far back from the end of the file to search for the footer GUID when it
+// is not at the expected offset, to allow for trailing padding.
+const FOOTER_SCAN_SIZE: usize = 0x10000;
+
 /// An entry that represents an area of pre-validated memory defined by the
 /// firmware in the IGVM file.
 #[derive(Clone, Copy, Debug, Default)]
+#[cfg_attr(feature = "json", derive(serde::Serialize))]
 pub struct OvmfFwMem {
     /// The base physical address of the prevalidated memory region.
     pub base: u32,
@@ -38,7 +67,8 @@ pub struct OvmfFwMem {
     pub size: u32,
 }
 
-#[derive(Clone, Copy, Default, Debug)]
+#[derive(Clone, Default, Debug)]
+#[cfg_attr(feature = "json", derive(serde::Serialize))]
 pub struct OvmfFwInfo {
     /// The guest physical address of the start of the guest firmware. The
     /// permissions on the pages in the firmware range are adjusted to the guest
@@ -62,19 +92,75 @@ pub struct OvmfFwInfo {
     /// CPUID page.
     pub cpuid_page: u32,
 
-    /// The SEV-ES reset vector for APs
+    /// The SEV-ES AP reset vector from the SEV info block, or zero if the
+    /// firmware does not provide one.
     pub reset_addr: u32,
 
-    /// The number of prevalidated memory regions defined by the firmware.
-    pub prevalidated_count: u32,
+    /// The region reserved for the kernel hashes table, or zero size if the
+    /// firmware does not define one.
+    pub kernel_hashes: OvmfFwMem,
+
+    /// The region reserved and prevalidated for an SVSM, or zero size if none
+    /// was requested. This is not described by the firmware metadata.
+    pub svsm_region: OvmfFwMem,
 
     /// The prevalidated memory regions defined by the firmware.
-    pub prevalidated: [OvmfFwMem; 8],
+    pub prevalidated: Vec<OvmfFwMem>,
+
+    /// Whether the firmware contains an OVMF SEV metadata table. Without it
+    /// the secrets, CAA and CPUID pages are not defined.
+    pub sev_metadata: bool,
 }
 
-struct MetadataDesc {
+impl OvmfFwInfo {
+    /// The ranges of guest memory populated from the firmware metadata on
+    /// SEV-SNP, including the firmware itself.
+    pub fn snp_regions(&self) -> Vec<(String, Range<u64>)> {
+        let fw_start = self.start as u64;
+        let mut regions = vec![(
+            "firmware".to_string(),
+            fw_start..fw_start + self.size as u64,
+        )];
+        for (name, page) in [
+            ("secrets page", self.secrets_page),
+            ("CAA page", self.caa_page),
+            ("CPUID page", self.cpuid_page),
+        ] {
+            if page != 0 {
+                regions.push((name.to_string(), page as u64..page as u64 + PAGE_SIZE_4K));
+            }
+        }
+        if self.kernel_hashes.size != 0 {
+            let base = self.kernel_hashes.base as u64;
+            regions.push((
+                "kernel hashes region".to_string(),
+                base..base + self.kernel_hashes.size as u64,
+            ));
+        }
+        if self.svsm_region.size != 0 {
+            let base = self.svsm_region.base as u64;
+            regions.push((
+                "SVSM region".to_string(),
+                base..base + self.svsm_region.size as u64,
+            ));
+        }
+        for (i, pv_mem) in self.prevalidated.iter().enumerate() {
+            regions.push((
+                format!("prevalidated region {i}"),
+                pv_mem.base as u64..pv_mem.base as u64 + pv_mem.size as u64,
+            ));
+        }
+        regions
+    }
+}
+
+/// A descriptor from the OVMF SEV metadata.
+#[derive(Clone, Copy, Debug)]
+#[cfg_attr(feature = "json", derive(serde::Serialize))]
+pub struct MetadataDesc {
     pub base: u32,
     pub len: u32,
+    #[cfg_attr(feature = "json", serde(rename = "type"))]
     pub metadata_type: u32,
 }
 
@@ -162,8 +248,20 @@ fn read_table(current_offset: usize, data: &[u8]) -> Result<TableInfo, Box<dyn E
     let entry_uuid = data[current_offset - uuid_size..current_offset].to_vec();
     let table_size_offset = current_offset - uuid_size - 2;
     let table_size = read_u16(&data[table_size_offset..table_size_offset + 2])? as usize;
+    // The size includes the size field and GUID, so anything smaller would
+    // underflow the data length.
+    if table_size < uuid_size + 2 {
+        return Err(format!(
+            "OVMF table ending at offset {current_offset:#X} has size {table_size:#X}, which is smaller than its {:#X} byte header",
+            uuid_size + 2
+        )
+        .into());
+    }
     if table_size > current_offset {
-        return Err("Invalid metadata table in OVMF firmware".into());
+        return Err(format!(
+            "OVMF table ending at offset {current_offset:#X} has size {table_size:#X}, which extends before the start of the firmware"
+        )
+        .into());
     }
     Ok(TableInfo {
         uuid: entry_uuid,
@@ -172,33 +270,83 @@ fn read_table(current_offset: usize, data: &[u8]) -> Result<TableInfo, Box<dyn E
     })
 }
 
+/// Get the address of a page described by the metadata. A page directive is
+/// emitted at the address so it must be page aligned.
+fn metadata_page(metadata_desc: &MetadataDesc, name: &str) -> Result<u32, Box<dyn Error>> {
+    if metadata_desc.base as u64 & (PAGE_SIZE_4K - 1) != 0 {
+        return Err(format!(
+            "The OVMF SEV metadata places the SEV-SNP {name} page at {:#X}, which is not 4K aligned",
+            metadata_desc.base
+        )
+        .into());
+    }
+    Ok(metadata_desc.base)
+}
+
 fn parse_sev_metadata(
     data: &[u8],
     table_data_offset: usize,
     firmware: &mut OvmfFwInfo,
+    ignored: &mut Vec<MetadataDesc>,
 ) -> Result<(), Box<dyn Error>> {
-    let offset = data.len() - read_u32(&data[table_data_offset..table_data_offset + 4])? as usize;
-    let metadata = SevMetadata::try_from(&data[offset..offset + SevMetadata::size()])?;
+    // The metadata location is given as an offset back from the end of the
+    // firmware, which must lie within the firmware.
+    let metadata_offset = read_u32(&data[table_data_offset..table_data_offset + 4])? as usize;
+    let offset = data.len().checked_sub(metadata_offset).ok_or(format!(
+        "OVMF SEV metadata offset {metadata_offset:#X} is outside the {:#X} byte firmware",
+        data.len()
+    ))?;
+    let metadata = SevMetadata::try_from(
+        data.get(offset..offset + SevMetadata::size())
+            .ok_or("OVMF SEV metadata header extends beyond the end of the firmware")?,
+    )?;
+
+    let descs_end = (metadata.num_desc as usize)
+        .checked_mul(MetadataDesc::size())
+        .and_then(|size| size.checked_add(offset + SevMetadata::size()));
+    if descs_end.is_none_or(|end| end > data.len()) {
+        return Err(format!(
+            "OVMF SEV metadata defines {} descriptors, which extend beyond the end of the firmware",
+            metadata.num_desc
+        )
+        .into());
+    }
 
+    firmware.sev_metadata = true;
     for i in 0..metadata.num_desc as usize {
         let desc_offset = offset + SevMetadata::size() + i * MetadataDesc::size();
         let metadata_desc =
             MetadataDesc::try_from(&data[desc_offset..desc_offset + MetadataDesc::size()])?;
+        // The regions are described with 32-bit addresses so must end at or
+        // below 4GB.
+        if metadata_desc.base as u64 + metadata_desc.len as u64 > 0x1_0000_0000 {
+            return Err(format!(
+                "OVMF SEV metadata descriptor {i} at {:#X} with length {:#X} extends beyond 4GB",
+                metadata_desc.base, metadata_desc.len
+            )
+            .into());
+        }
         match metadata_desc.metadata_type {
             SEV_META_DESC_TYPE_MEM => {
-                if firmware.prevalidated_count as usize == firmware.prevalidated.len() {
-                    return Err("OVMF metadata defines too many memory regions".into());
-                }
-                firmware.prevalidated[firmware.prevalidated_count as usize].base =
-                    metadata_desc.base;
-                firmware.prevalidated[firmware.prevalidated_count as usize].size =
-                    metadata_desc.len;
-                firmware.prevalidated_count += 1;
+                firmware.prevalidated.push(OvmfFwMem {
+                    base: metadata_desc.base,
+                    size: metadata_desc.len,
+                });
+            }
+            SEV_META_DESC_TYPE_SECRETS => {
+                firmware.secrets_page = metadata_page(&metadata_desc, "secrets")?
+            }
+            SEV_META_DESC_TYPE_CPUID => {
+                firmware.cpuid_page = metadata_page(&metadata_desc, "CPUID")?
+            }
+            SEV_META_DESC_TYPE_CAA => {
+                firmware.caa_page = metadata_page(&metadata_desc, "calling area")?
             }
-            SEV_META_DESC_TYPE_SECRETS => firmware.secrets_page = metadata_desc.base,
-            SEV_META_DESC_TYPE_CPUID => firmware.cpuid_page = metadata_desc.base,
-            SEV_META_DESC_TYPE_CAA => firmware.caa_page = metadata_desc.base,
-            _ => {}
+            SEV_META_DESC_TYPE_KERNEL_HASHES => {
+                firmware.kernel_hashes.base = metadata_desc.base;
+                firmware.kernel_hashes.size = metadata_desc.len;
+            }
+            _ => ignored.push(metadata_desc),
         }
     }
 
@@ -206,7 +354,9 @@ fn parse_sev_metadata(
 }
 
 fn parse_sev_info_block(data: &[u8], firmware: &mut OvmfFwInfo) -> Result<(), Box<dyn Error>> {
-    // Not currently used
+    if data.len() < 4 {
+        return Err("Invalid SEV-ES reset block in OVMF firmware".into());
+    }
     firmware.reset_addr = read_u32(&data[0..4])?;
     Ok(())
 }
@@ -215,11 +365,12 @@ fn parse_inner_table(
     current_offset: usize,
     data: &[u8],
     firmware: &mut OvmfFwInfo,
+    ignored: &mut Vec<MetadataDesc>,
 ) -> Result<usize, Box<dyn Error>> {
     let table = read_table(current_offset, data)?;
 
     if table.uuid == OVMF_SEV_METADATA_GUID.to_bytes_le() {
-        parse_sev_metadata(data, table.data_offset, firmware)?;
+        parse_sev_metadata(data, table.data_offset, firmware, ignored)?;
     } else if table.uuid == SEV_INFO_BLOCK_GUID.to_bytes_le() {
         parse_sev_info_block(
             &data[table.data_offset..table.data_offset + table.data_length as usize],
@@ -230,117 +381,851 @@ fn parse_inner_table(
     Ok(table.data_offset)
 }
 
-pub fn parse_ovmf(data: &[u8], firmware: &mut OvmfFwInfo) -> Result<(), Box<dyn Error>> {
+/// Find the padding that build tooling has appended after the firmware by
+/// searching backwards for the table footer GUID when it is not at the
+/// expected offset from the end. Returns the length of the firmware without
+/// the padding, or None if the footer is where it is expected or cannot be
+/// found.
+fn find_trailing_padding(data: &[u8]) -> Option<usize> {
+    let guid = OVMF_TABLE_FOOTER_GUID.to_bytes_le();
+    let expected_end = data.len().checked_sub(FOOTER_OFFSET)?;
+    if expected_end >= guid.len() && data[expected_end - guid.len()..expected_end] == guid {
+        return None;
+    }
+    let scan_start = expected_end.saturating_sub(FOOTER_SCAN_SIZE);
+    data[scan_start..expected_end]
+        .windows(guid.len())
+        .rposition(|window| window == guid)
+        .map(|pos| scan_start + pos + guid.len() + FOOTER_OFFSET)
+}
+
+/// Parse the OVMF GUIDed table at the end of an in-memory firmware image and
+/// fill in the fields of `firmware` that are described by the metadata. The
+/// firmware start and size are not set as they depend on where the firmware
+/// is placed. SEV metadata descriptors of an unsupported type are returned so
+/// they can be reported.
+pub fn parse_ovmf(
+    data: &[u8],
+    firmware: &mut OvmfFwInfo,
+) -> Result<Vec<MetadataDesc>, Box<dyn Error>> {
     // The OVMF metadata UUID is stored at a specific offset from the end of the file.
     let mut current_offset = data
         .len()
         .checked_sub(FOOTER_OFFSET)
+        .filter(|offset| *offset >= size_of::<Uuid>())
         .ok_or("OVMF firmware file is too small")?;
-    let ovmf_table = read_table(current_offset, data)?;
-    if ovmf_table.uuid != OVMF_TABLE_FOOTER_GUID.to_bytes_le() {
-        return Err("OVMF table footer not found".into());
+    // Check the footer before trusting the table size that precedes it.
+    let footer_uuid = &data[current_offset - size_of::<Uuid>()..current_offset];
+    if footer_uuid != OVMF_TABLE_FOOTER_GUID.to_bytes_le() {
+        let found = Uuid::from_slice_le(footer_uuid)?;
+        return Err(format!(
+            "OVMF table footer not found: expected {OVMF_TABLE_FOOTER_GUID} ending at offset {current_offset:#X} ({FOOTER_OFFSET} bytes from the end of the {:#X} byte file) but found {found}",
+            data.len()
+        )
+        .into());
     }
+    let ovmf_table = read_table(current_offset, data)?;
     current_offset = ovmf_table.data_offset + ovmf_table.data_length as usize;
 
+    let mut ignored = Vec::new();
     while current_offset > ovmf_table.data_offset {
-        current_offset = parse_inner_table(current_offset, data, firmware)?;
+        let next_offset = parse_inner_table(current_offset, data, firmware, &mut ignored)?;
+        // Each table must move the walk towards the start of the footer,
+        // otherwise a malformed table would loop forever.
+        if next_offset >= current_offset {
+            return Err(format!(
+                "Invalid metadata table in OVMF firmware at offset {current_offset:#X}"
+            )
+            .into());
+        }
+        current_offset = next_offset;
     }
 
+    Ok(ignored)
+}
+
+/// Check that the reset vector in the last 16 bytes of the firmware looks like
+/// a jump, as it does in OVMF. This catches files that are not firmware but
+/// happen to contain the table footer GUID.
+pub fn check_reset_vector(data: &[u8]) -> Result<(), String> {
+    let reset_vector = data
+        .len()
+        .checked_sub(RESET_VECTOR_SIZE)
+        .map(|offset| &data[offset..])
+        .ok_or("The firmware is too small to contain a reset vector")?;
+    // OVMF may pad the jump with NOPs.
+    match reset_vector.iter().find(|&&byte| byte != OPCODE_NOP) {
+        Some(&(OPCODE_JMP_FAR | OPCODE_JMP_NEAR | OPCODE_JMP_SHORT)) => Ok(()),
+        _ => Err(format!(
+            "The firmware reset vector does not start with a jump: {}",
+            to_hex(reset_vector)
+        )),
+    }
+}
+
+fn check_firmware_size(len: usize) -> Result<(), Box<dyn Error>> {
+    if len > MAX_FIRMWARE_SIZE {
+        return Err(format!(
+            "OVMF firmware size {len:#X} is too large. The maximum size is {MAX_FIRMWARE_SIZE:#X}"
+        )
+        .into());
+    }
     Ok(())
 }
 
+/// Check that the regions populated for SEV-SNP are page aligned and do not
+/// overlap each other or the firmware, which would result in more than one
+/// page directive for the same GPA.
+fn validate_snp_regions(fw_info: &OvmfFwInfo) -> Vec<String> {
+    let regions = fw_info.snp_regions();
+    let mut issues = Vec::new();
+    for (name, range) in &regions {
+        if range.start & (PAGE_SIZE_4K - 1) != 0 {
+            issues.push(format!(
+                "The {name} at {:#X} is not page aligned",
+                range.start
+            ));
+        }
+    }
+    for (i, (name, range)) in regions.iter().enumerate() {
+        for (other_name, other_range) in &regions[i + 1..] {
+            if range.start < other_range.end && other_range.start < range.end {
+                issues.push(format!(
+                    "The {name} at {:#X}-{:#X} overlaps the {other_name} at {:#X}-{:#X}",
+                    range.start, range.end, other_range.start, other_range.end
+                ));
+            }
+        }
+    }
+    issues
+}
+
+/// Options for the SEV-SNP pages described by the OVMF metadata.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct SnpPageOptions<'a> {
+    /// Guest physical addresses that override the metadata.
+    pub secrets_page: Option<u32>,
+    pub caa_page: Option<u32>,
+    pub cpuid_page: Option<u32>,
+
+    /// Do not populate the secrets, CAA and CPUID pages. Their addresses are
+    /// still reported in the firmware information so another component can
+    /// populate them.
+    pub skip_pages: bool,
+
+    /// Warn instead of failing when the regions overlap.
+    pub allow_overlap: bool,
+
+    /// Mark the calling area page as unmeasured. The secrets page is always
+    /// measured with the secrets page type, as the SEV-SNP firmware requires.
+    pub unmeasured_caa: bool,
+
+    /// Populate the 2MB aligned parts of the prevalidated regions with 2MB
+    /// zero pages instead of 4K zero pages.
+    pub coalesce_zero_pages: bool,
+
+    /// Base and size of a region to reserve and prevalidate for an SVSM.
+    pub svsm_region: Option<(u32, u32)>,
+
+    /// Base and size of additional prevalidated regions, which are added
+    /// after the regions defined by the metadata.
+    pub prevalidated: &'a [(u32, u32)],
+
+    /// Indices of the prevalidated regions whose pages are marked as
+    /// unmeasured so they are accepted without being part of the launch
+    /// measurement.
+    pub unmeasured_prevalidated: &'a [u32],
+
+    /// Fail rather than warn if the firmware has no SEV metadata.
+    pub require_metadata: bool,
+
+    /// Contents of the secrets page. This is normally empty so that the
+    /// SEV-SNP firmware fills the page, and is only set for testing.
+    pub secrets_data: Option<&'a [u8]>,
+}
+
+/// Build zero page directives that prevalidate a region with the given flags.
+/// When coalescing, the 2MB aligned parts of the region use 2MB pages.
+fn build_zero_pages(
+    directives: &mut Vec<IgvmDirectiveHeader>,
+    region: OvmfFwMem,
+    compatibility_mask: u32,
+    flags: IgvmPageDataFlags,
+    coalesce: bool,
+) {
+    let end = region.base as u64 + region.size as u64;
+    let mut gpa = region.base as u64;
+    while gpa < end {
+        let is_2mb_page = coalesce && gpa & (PAGE_SIZE_2M - 1) == 0 && end - gpa >= PAGE_SIZE_2M;
+        directives.push(IgvmDirectiveHeader::PageData {
+            gpa,
+            compatibility_mask,
+            flags: flags.with_is_2mb_page(is_2mb_page),
+            data_type: IgvmPageDataType::NORMAL,
+            data: vec![],
+        });
+        gpa += if is_2mb_page {
+            PAGE_SIZE_2M
+        } else {
+            PAGE_SIZE_4K
+        };
+    }
+}
+
 pub struct OvmfFirmware {
+    data: Vec<u8>,
     fw_info: OvmfFwInfo,
+    ignored_metadata: Vec<MetadataDesc>,
+    warnings: Vec<String>,
     directives: Vec<IgvmDirectiveHeader>,
 }
 
 impl OvmfFirmware {
     pub fn parse(
         filename: &String,
-        compatibility_mask: u32,
-        platform: Platform,
+        compatibility_masks: &[(Platform, u32)],
+        pad: bool,
+        base: Option<u32>,
+        cpuid_data: Option<&[u8]>,
+        snp_pages: SnpPageOptions<'_>,
+        expected_sha256: Option<&[u8; SHA256_DIGEST_SIZE]>,
     ) -> Result<Self, Box<dyn Error>> {
-        let mut in_file = File::open(filename).map_err(|e| {
-            eprintln!("Failed to open firmware file {}", filename);
-            e
-        })?;
-        let len = in_file.metadata()?.len() as usize;
-        if len > 0xffffffff {
-            return Err("OVMF firmware is too large".into());
+        let data = if filename == "-" {
+            // The size of stdin is not known up front so read one byte more
+            // than the limit to detect firmware that is too large.
+            let mut data = Vec::new();
+            std::io::stdin()
+                .lock()
+                .take(MAX_FIRMWARE_SIZE as u64 + 1)
+                .read_to_end(&mut data)
+                .inspect_err(|_| {
+                    eprintln!("Failed to read firmware from stdin");
+                })?;
+            check_firmware_size(data.len())?;
+            data
+        } else {
+            let mut in_file = File::open(filename).inspect_err(|_| {
+                eprintln!("Failed to open firmware file {}", filename);
+            })?;
+            let len = in_file.metadata()?.len() as usize;
+            check_firmware_size(len)?;
+            let mut data = Vec::with_capacity(len);
+            if in_file.read_to_end(&mut data)? != len {
+                return Err("Failed to read OVMF file".into());
+            }
+            data
+        };
+        if let Some(expected) = expected_sha256 {
+            // Check the file as provided so the digest can be compared with
+            // the output of sha256sum.
+            let digest = sha256(&data);
+            if digest != *expected {
+                return Err(format!(
+                    "The SHA-256 of the firmware file {} is {} but {} was expected",
+                    filename,
+                    to_hex(&digest),
+                    to_hex(expected)
+                )
+                .into());
+            }
         }
-        let mut data = Vec::with_capacity(len);
-        if in_file.read_to_end(&mut data)? != len {
-            return Err("Failed to read OVMF file".into());
+        // Compressed firmware is checked again once decompressed, which
+        // stops as soon as the limit is exceeded.
+        let data = decompress(data, filename, MAX_FIRMWARE_SIZE)?;
+        check_firmware_size(data.len())?;
+        Self::from_bytes(data, compatibility_masks, pad, base, cpuid_data, snp_pages)
+    }
+
+    /// Build the firmware directives from a firmware image that has already
+    /// been read into memory and decompressed.
+    pub fn from_bytes(
+        mut data: Vec<u8>,
+        compatibility_masks: &[(Platform, u32)],
+        pad: bool,
+        base: Option<u32>,
+        cpuid_data: Option<&[u8]>,
+        snp_pages: SnpPageOptions<'_>,
+    ) -> Result<Self, Box<dyn Error>> {
+        let mut warnings = Vec::new();
+        // The metadata is located relative to the end of the firmware, so any
+        // trailing padding is removed before it is parsed.
+        if let Some(len) = find_trailing_padding(&data) {
+            warnings.push(format!(
+                "Removing {:#X} bytes of padding after the OVMF table footer",
+                data.len() - len
+            ));
+            data.truncate(len);
         }
+        let len = data.len();
+        check_firmware_size(len)?;
         let mut fw_info = OvmfFwInfo::default();
-        parse_ovmf(&data, &mut fw_info)?;
+        let ignored_metadata = parse_ovmf(&data, &mut fw_info)?;
 
-        // OVMF must be located to end at 4GB.
-        fw_info.start = (0xffffffff - len + 1) as u32;
+        // Partial pages would be measured differently by the loader, so the
+        // firmware must cover a whole number of pages. When padding is
+        // requested, zeros are inserted at the start of the firmware so the
+        // top of the image remains anchored at 4GB.
+        let remainder = len % PAGE_SIZE_4K as usize;
+        if remainder != 0 {
+            if !pad {
+                return Err(format!(
+                    "OVMF firmware size {len:#X} is not a multiple of the page size {PAGE_SIZE_4K:#X}"
+                )
+                .into());
+            }
+            let padding = PAGE_SIZE_4K as usize - remainder;
+            data.splice(0..0, vec![0u8; padding]);
+        }
+        let len = data.len();
+
+        // OVMF is located to end at 4GB unless an explicit base is provided.
+        fw_info.start = match base {
+            Some(base) => {
+                if base as u64 & (PAGE_SIZE_4K - 1) != 0 {
+                    return Err(format!("The firmware base {base:#X} is not page aligned").into());
+                }
+                if base as u64 + len as u64 > 0x100000000 {
+                    return Err(format!(
+                        "The firmware at base {base:#X} with size {len:#X} extends beyond 4GB"
+                    )
+                    .into());
+                }
+                base
+            }
+            None => (0xffffffff - len + 1) as u32,
+        };
         fw_info.size = len as u32;
 
-        // Build page directives for the file contents.
-        let mut gpa: u64 = fw_info.start.into();
-        let mut directives = Vec::<IgvmDirectiveHeader>::new();
-        for page_data in data.chunks(PAGE_SIZE_4K as usize) {
-            directives.push(IgvmDirectiveHeader::PageData {
-                gpa,
-                compatibility_mask,
-                flags: IgvmPageDataFlags::new(),
-                data_type: IgvmPageDataType::NORMAL,
-                data: page_data.to_vec(),
-            });
-            gpa += PAGE_SIZE_4K;
+        if compatibility_masks
+            .iter()
+            .any(|(platform, _)| *platform == Platform::SevSnp)
+        {
+            // Without the SEV metadata the secrets, CAA and CPUID pages are
+            // only populated if their addresses are given on the command line.
+            if !fw_info.sev_metadata {
+                let message = "The firmware contains no OVMF SEV metadata so the SEV-SNP secrets, CAA and CPUID pages are not defined by the firmware";
+                if snp_pages.require_metadata {
+                    return Err(message.into());
+                }
+                warnings.push(message.to_string());
+            }
+            fw_info.secrets_page = snp_pages.secrets_page.unwrap_or(fw_info.secrets_page);
+            fw_info.caa_page = snp_pages.caa_page.unwrap_or(fw_info.caa_page);
+            fw_info.cpuid_page = snp_pages.cpuid_page.unwrap_or(fw_info.cpuid_page);
+            if let Some((base, size)) = snp_pages.svsm_region {
+                fw_info.svsm_region = OvmfFwMem { base, size };
+            }
+            fw_info.prevalidated.extend(
+                snp_pages
+                    .prevalidated
+                    .iter()
+                    .map(|&(base, size)| OvmfFwMem { base, size }),
+            );
+            // The secrets and CPUID pages are required to launch an SEV-SNP
+            // guest so must not silently default to GPA zero. The calling area
+            // is only used with an SVSM and is omitted if it is not defined.
+            if fw_info.secrets_page == 0 && !snp_pages.skip_pages {
+                return Err(
+                    "The firmware does not define an SEV-SNP secrets page. Use --secrets-gpa to specify one"
+                        .into(),
+                );
+            }
+            if fw_info.cpuid_page == 0 && !snp_pages.skip_pages {
+                return Err(
+                    "The firmware does not define an SEV-SNP CPUID page. Use --cpuid-gpa to specify one"
+                        .into(),
+                );
+            }
+            if let Some(index) = snp_pages
+                .unmeasured_prevalidated
+                .iter()
+                .find(|index| **index as usize >= fw_info.prevalidated.len())
+            {
+                return Err(format!(
+                    "Prevalidated region {index} cannot be unmeasured as there are only {} prevalidated regions",
+                    fw_info.prevalidated.len()
+                )
+                .into());
+            }
+            let issues = validate_snp_regions(&fw_info);
+            if !snp_pages.allow_overlap && !issues.is_empty() {
+                return Err(issues.join("\n").into());
+            }
+            warnings.extend(issues);
         }
 
-        if let Platform::SevSnp = platform {
-            // Build page directives for the metadata
-            directives.push(IgvmDirectiveHeader::PageData {
-                gpa: fw_info.secrets_page as u64,
-                compatibility_mask,
-                flags: IgvmPageDataFlags::new(),
-                data_type: IgvmPageDataType::SECRETS,
-                data: vec![],
-            });
-            directives.push(IgvmDirectiveHeader::PageData {
-                gpa: fw_info.caa_page as u64,
-                compatibility_mask,
-                flags: IgvmPageDataFlags::new(),
-                data_type: IgvmPageDataType::NORMAL,
-                data: vec![],
-            });
-            directives.push(IgvmDirectiveHeader::PageData {
-                gpa: fw_info.cpuid_page as u64,
-                compatibility_mask,
-                flags: IgvmPageDataFlags::new(),
-                data_type: IgvmPageDataType::CPUID_DATA,
-                data: vec![],
-            });
-            for i in 0..fw_info.prevalidated_count {
-                let pv_mem = fw_info.prevalidated[i as usize];
-                for offset in (0..pv_mem.size).step_by(PAGE_SIZE_4K as usize) {
+        // The firmware contents are common to all platforms.
+        let all_platforms_mask = compatibility_masks
+            .iter()
+            .fold(0, |mask, (_, platform_mask)| mask | platform_mask);
+
+        // Build page directives for the file contents. The pages use the
+        // default flags: IGVM page data has no read-only attribute and the
+        // OVMF metadata does not describe any region attributes. Marking the
+        // flash pages as shared would instead remove them from the launch
+        // measurement and expose them to the host.
+        let mut directives = build_page_data(&data, fw_info.start.into(), all_platforms_mask);
+
+        for &(platform, compatibility_mask) in compatibility_masks {
+            if let Platform::SevSnp = platform {
+                // Build page directives for the metadata
+                if !snp_pages.skip_pages {
                     directives.push(IgvmDirectiveHeader::PageData {
-                        gpa: (pv_mem.base + offset) as u64,
+                        gpa: fw_info.secrets_page as u64,
+                        compatibility_mask,
+                        flags: IgvmPageDataFlags::new(),
+                        data_type: IgvmPageDataType::SECRETS,
+                        data: snp_pages
+                            .secrets_data
+                            .map(|data| data.to_vec())
+                            .unwrap_or_default(),
+                    });
+                    if fw_info.caa_page != 0 {
+                        directives.push(IgvmDirectiveHeader::PageData {
+                            gpa: fw_info.caa_page as u64,
+                            compatibility_mask,
+                            flags: IgvmPageDataFlags::new()
+                                .with_unmeasured(snp_pages.unmeasured_caa),
+                            data_type: IgvmPageDataType::NORMAL,
+                            data: vec![],
+                        });
+                    }
+                    directives.push(IgvmDirectiveHeader::PageData {
+                        gpa: fw_info.cpuid_page as u64,
+                        compatibility_mask,
+                        flags: IgvmPageDataFlags::new(),
+                        data_type: IgvmPageDataType::CPUID_DATA,
+                        data: cpuid_data.map(|data| data.to_vec()).unwrap_or_default(),
+                    });
+                }
+                for offset in (0..fw_info.kernel_hashes.size).step_by(PAGE_SIZE_4K as usize) {
+                    directives.push(IgvmDirectiveHeader::PageData {
+                        gpa: fw_info.kernel_hashes.base as u64 + offset as u64,
                         compatibility_mask,
                         flags: IgvmPageDataFlags::new(),
                         data_type: IgvmPageDataType::NORMAL,
                         data: vec![],
                     });
                 }
+                for (i, pv_mem) in fw_info.prevalidated.iter().enumerate() {
+                    let unmeasured = snp_pages.unmeasured_prevalidated.contains(&(i as u32));
+                    build_zero_pages(
+                        &mut directives,
+                        *pv_mem,
+                        compatibility_mask,
+                        IgvmPageDataFlags::new().with_unmeasured(unmeasured),
+                        snp_pages.coalesce_zero_pages,
+                    );
+                }
+                build_zero_pages(
+                    &mut directives,
+                    fw_info.svsm_region,
+                    compatibility_mask,
+                    IgvmPageDataFlags::new(),
+                    snp_pages.coalesce_zero_pages,
+                );
             }
         }
 
         Ok(Self {
+            data,
             fw_info,
+            ignored_metadata,
+            warnings,
             directives,
         })
     }
+}
+
+impl FirmwareSource for OvmfFirmware {
+    fn take_directives(&mut self) -> Vec<IgvmDirectiveHeader> {
+        std::mem::take(&mut self.directives)
+    }
+
+    fn fw_info(&self) -> &OvmfFwInfo {
+        &self.fw_info
+    }
+
+    fn data(&self) -> &[u8] {
+        &self.data
+    }
+
+    fn ignored_metadata(&self) -> &[MetadataDesc] {
+        &self.ignored_metadata
+    }
+
+    fn warnings(&self) -> &[String] {
+        &self.warnings
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::common::*;
+
+    fn parse(data: &[u8]) -> Result<(OvmfFwInfo, Vec<MetadataDesc>), Box<dyn Error>> {
+        let mut fw_info = OvmfFwInfo::default();
+        let ignored = parse_ovmf(data, &mut fw_info)?;
+        Ok((fw_info, ignored))
+    }
+
+    fn snp_firmware(image: &TestImage) -> Result<OvmfFirmware, Box<dyn Error>> {
+        OvmfFirmware::from_bytes(
+            image.build(),
+            &[(Platform::SevSnp, 1)],
+            false,
+            None,
+            None,
+            SnpPageOptions::default(),
+        )
+    }
+
+    #[test]
+    fn valid_image() {
+        let mut image = TestImage::default();
+        image.descriptors.push((0x810000, 0x2000, 0x20));
+        let (fw_info, ignored) = parse(&image.build()).unwrap();
+        assert!(fw_info.sev_metadata);
+        assert_eq!(fw_info.secrets_page, TEST_SECRETS_PAGE);
+        assert_eq!(fw_info.cpuid_page, TEST_CPUID_PAGE);
+        assert_eq!(fw_info.caa_page, TEST_CAA_PAGE);
+        assert_eq!(fw_info.reset_addr, TEST_RESET_ADDR);
+        let prevalidated: Vec<_> = fw_info
+            .prevalidated
+            .iter()
+            .map(|mem| (mem.base, mem.size))
+            .collect();
+        assert_eq!(prevalidated, [(0x800000, 0x9000), (0x80c000, 0x4000)]);
+        assert_eq!(ignored.len(), 1);
+        assert_eq!(ignored[0].metadata_type, 0x20);
+        check_reset_vector(&image.build()).unwrap();
+    }
+
+    #[test]
+    fn valid_image_directives() {
+        let mut firmware = snp_firmware(&TestImage::default()).unwrap();
+        let fw_info = firmware.fw_info();
+        assert_eq!(fw_info.start, 0xfffe0000);
+        assert_eq!(fw_info.size, 0x20000);
+        let directives = firmware.take_directives();
+        let secrets = directives.iter().filter(|directive| {
+            matches!(directive, IgvmDirectiveHeader::PageData { gpa, data_type: IgvmPageDataType::SECRETS, .. }
+                if *gpa == TEST_SECRETS_PAGE as u64)
+        });
+        assert_eq!(secrets.count(), 1);
+        // 0x20 firmware pages, the secrets, CAA and CPUID pages and 13
+        // prevalidated pages.
+        assert_eq!(directives.len(), 0x20 + 3 + 13);
+    }
+
+    #[test]
+    fn image_without_metadata() {
+        let image = TestImage {
+            metadata: false,
+            ..Default::default()
+        };
+        let (fw_info, _) = parse(&image.build()).unwrap();
+        assert!(!fw_info.sev_metadata);
+        assert_eq!(fw_info.secrets_page, 0);
+        assert_eq!(fw_info.reset_addr, TEST_RESET_ADDR);
+    }
+
+    #[test]
+    fn unknown_tables_are_skipped() {
+        let image = TestImage::default();
+        let mut inner = table(&[0xaa; 7], uuid!("11111111-2222-3333-4444-555555555555"));
+        inner.extend(image.inner_tables());
+        let data = image.build_with_footer(&table(&inner, OVMF_TABLE_FOOTER_GUID));
+        let (fw_info, _) = parse(&data).unwrap();
+        assert_eq!(fw_info.secrets_page, TEST_SECRETS_PAGE);
+    }
+
+    #[test]
+    fn missing_footer_is_rejected() {
+        let image = TestImage::default();
+        let data = image.build_with_footer(&table(
+            &image.inner_tables(),
+            uuid!("11111111-2222-3333-4444-555555555555"),
+        ));
+        let err = parse(&data).unwrap_err().to_string();
+        assert!(err.contains("OVMF table footer not found"), "{err}");
+        assert!(parse(&[0u8; 16]).is_err());
+    }
+
+    #[test]
+    fn footer_larger_than_image_is_rejected() {
+        let image = TestImage::default();
+        let mut footer = table(&image.inner_tables(), OVMF_TABLE_FOOTER_GUID);
+        let size_offset = footer.len() - 18;
+        footer[size_offset..size_offset + 2].copy_from_slice(&0xffffu16.to_le_bytes());
+        let data = TestImage {
+            size: 0x2000,
+            metadata: false,
+            ..Default::default()
+        }
+        .build_with_footer(&footer);
+        let err = parse(&data).unwrap_err().to_string();
+        assert!(err.contains("extends before the start"), "{err}");
+    }
+
+    #[test]
+    fn reset_block_sets_the_reset_address() {
+        let image = TestImage {
+            metadata: false,
+            reset_addr: Some(0xffffe000),
+            ..Default::default()
+        };
+        let (fw_info, _) = parse(&image.build()).unwrap();
+        assert_eq!(fw_info.reset_addr, 0xffffe000);
+    }
+
+    #[test]
+    fn truncated_reset_block_is_rejected() {
+        let image = TestImage {
+            reset_addr: None,
+            ..Default::default()
+        };
+        let mut inner = image.inner_tables();
+        inner.extend(table(&[0x04, 0xb0], SEV_INFO_BLOCK_GUID));
+        let data = image.build_with_footer(&table(&inner, OVMF_TABLE_FOOTER_GUID));
+        let err = parse(&data).unwrap_err().to_string();
+        assert!(err.contains("SEV-ES reset block"), "{err}");
+    }
+
+    #[test]
+    fn overlapping_prevalidated_regions() {
+        let mut image = TestImage::default();
+        image
+            .descriptors
+            .push((0x808000, 0x2000, SEV_META_DESC_TYPE_MEM));
+        let err = snp_firmware(&image).err().unwrap().to_string();
+        assert!(
+            err.contains("secrets page at 0x809000-0x80A000 overlaps the prevalidated region 2"),
+            "{err}"
+        );
+        assert!(
+            err.contains("prevalidated region 0 at 0x800000-0x809000 overlaps"),
+            "{err}"
+        );
+
+        let firmware = OvmfFirmware::from_bytes(
+            image.build(),
+            &[(Platform::SevSnp, 1)],
+            false,
+            None,
+            None,
+            SnpPageOptions {
+                allow_overlap: true,
+                ..Default::default()
+            },
+        )
+        .unwrap();
+        assert!(firmware
+            .warnings()
+            .iter()
+            .any(|warning| warning.contains("overlaps")));
+    }
+
+    #[test]
+    fn prevalidated_region_overlapping_the_firmware() {
+        let mut image = TestImage::default();
+        image
+            .descriptors
+            .push((0xfffff000, 0x1000, SEV_META_DESC_TYPE_MEM));
+        let err = snp_firmware(&image).err().unwrap().to_string();
+        assert!(err.contains("overlaps"), "{err}");
+        assert!(snp_firmware(&TestImage::default()).is_ok());
+    }
+
+    #[test]
+    fn firmware_size_limit() {
+        assert!(check_firmware_size(MAX_FIRMWARE_SIZE).is_ok());
+        let err = check_firmware_size(MAX_FIRMWARE_SIZE + 1)
+            .unwrap_err()
+            .to_string();
+        assert!(err.contains("The maximum size is 0xFFFFF000"), "{err}");
+        assert!(check_firmware_size(usize::MAX).is_err());
+    }
+
+    #[test]
+    fn firmware_base_must_fit_below_4gb() {
+        let from_base = |base| {
+            OvmfFirmware::from_bytes(
+                TestImage::default().build(),
+                &[(Platform::Native, 1)],
+                false,
+                Some(base),
+                None,
+                SnpPageOptions::default(),
+            )
+        };
+        assert_eq!(from_base(0xfffe0000).unwrap().fw_info().start, 0xfffe0000);
+        let err = from_base(0xffff0000).err().unwrap().to_string();
+        assert!(err.contains("extends beyond 4GB"), "{err}");
+    }
+
+    #[test]
+    fn table_walk_terminates() {
+        let image = TestImage::default();
+        let inner = image.inner_tables();
+        let mut data = image.build();
+        // The size field of the last inner table, which is walked first.
+        let size_offset = image.size - FOOTER_OFFSET - 18 - 18;
+        let valid_size = u16::from_le_bytes([data[size_offset], data[size_offset + 1]]);
+        assert_eq!(valid_size as usize, 22);
+        for size in 0..=inner.len() as u16 + 0x20 {
+            data[size_offset..size_offset + 2].copy_from_slice(&size.to_le_bytes());
+            let result = parse(&data);
+            if size == 0 {
+                let err = result.unwrap_err().to_string();
+                assert!(err.contains("smaller than its 0x12 byte header"), "{err}");
+            } else if size == valid_size {
+                assert!(result.is_ok());
+            }
+        }
+    }
+
+    fn parse_with_metadata_offset(offset_from_end: u32) -> Result<OvmfFwInfo, Box<dyn Error>> {
+        let image = TestImage {
+            metadata: false,
+            ..Default::default()
+        };
+        let mut inner = table(&offset_from_end.to_le_bytes(), OVMF_SEV_METADATA_GUID);
+        inner.extend(image.inner_tables());
+        let data = image.build_with_footer(&table(&inner, OVMF_TABLE_FOOTER_GUID));
+        parse(&data).map(|(fw_info, _)| fw_info)
+    }
+
+    #[test]
+    fn metadata_offset_bounds() {
+        let valid = (TestImage::default().size - TestImage::METADATA_OFFSET) as u32;
+        assert_eq!(
+            parse_with_metadata_offset(valid).unwrap().secrets_page,
+            TEST_SECRETS_PAGE
+        );
+        let err = parse_with_metadata_offset(0xffffffff)
+            .unwrap_err()
+            .to_string();
+        assert!(
+            err.contains("is outside the 0x20000 byte firmware"),
+            "{err}"
+        );
+        let err = parse_with_metadata_offset(8).unwrap_err().to_string();
+        assert!(err.contains("header extends beyond the end"), "{err}");
+    }
+
+    #[test]
+    fn descriptor_count_bounds() {
+        let image = TestImage::default();
+        let num_desc_offset = TestImage::METADATA_OFFSET + 12;
+        let with_num_desc = |num_desc: u32| {
+            let mut data = image.build();
+            data[num_desc_offset..num_desc_offset + 4].copy_from_slice(&num_desc.to_le_bytes());
+            parse(&data)
+        };
+        let (fw_info, _) = with_num_desc(image.descriptors.len() as u32).unwrap();
+        assert_eq!(fw_info.caa_page, TEST_CAA_PAGE);
+        let too_many = (image.size - TestImage::METADATA_OFFSET - 16) / 12 + 1;
+        for num_desc in [too_many as u32, u32::MAX] {
+            let err = with_num_desc(num_desc).unwrap_err().to_string();
+            assert!(
+                err.contains("extend beyond the end of the firmware"),
+                "{err}"
+            );
+        }
+    }
+
+    #[test]
+    fn metadata_pages_must_be_aligned() {
+        for (index, name) in [(1, "secrets"), (2, "CPUID"), (3, "calling area")] {
+            let mut image = TestImage::default();
+            image.descriptors[index].0 += 0x10;
+            let err = parse(&image.build()).unwrap_err().to_string();
+            assert!(
+                err.contains(&format!("SEV-SNP {name} page at")) && err.contains("not 4K aligned"),
+                "{err}"
+            );
+        }
+        assert!(parse(&TestImage::default().build()).is_ok());
+    }
+
+    #[test]
+    fn table_size_smaller_than_header() {
+        let image = TestImage::default();
+        let mut data = image.build();
+        let size_offset = image.size - FOOTER_OFFSET - 18;
+        for (size, valid) in [(1u16, false), (17, false), (18, true)] {
+            data[size_offset..size_offset + 2].copy_from_slice(&size.to_le_bytes());
+            let result = read_table(image.size - FOOTER_OFFSET, &data);
+            assert_eq!(result.is_ok(), valid, "table size {size}");
+        }
+        // An empty footer table contains no inner tables.
+        let (fw_info, _) = parse(&data).unwrap();
+        assert!(!fw_info.sev_metadata);
+        data[size_offset..size_offset + 2].copy_from_slice(&1u16.to_le_bytes());
+        let err = parse(&data).unwrap_err().to_string();
+        assert!(err.contains("has size 0x1, which is smaller"), "{err}");
+    }
+
+    #[test]
+    fn trailing_padding_is_removed() {
+        assert_eq!(find_trailing_padding(&TestImage::default().build()), None);
+
+        let image = TestImage {
+            padding: 0x200,
+            ..Default::default()
+        };
+        assert_eq!(find_trailing_padding(&image.build()), Some(image.size));
+        let firmware = snp_firmware(&image).unwrap();
+        assert_eq!(firmware.fw_info().size, 0x20000);
+        assert_eq!(firmware.fw_info().secrets_page, TEST_SECRETS_PAGE);
+        assert!(firmware.warnings()[0].contains("Removing 0x200 bytes of padding"));
+
+        let image = TestImage {
+            padding: FOOTER_SCAN_SIZE + 0x1000,
+            ..Default::default()
+        };
+        assert_eq!(find_trailing_padding(&image.build()), None);
+        let err = snp_firmware(&image).err().unwrap().to_string();
+        assert!(err.contains("OVMF table footer not found"), "{err}");
+    }
 
-    pub fn directives(&self) -> &Vec<IgvmDirectiveHeader> {
-        &self.directives
+    #[test]
+    fn descriptors_must_end_below_4gb() {
+        let mut image = TestImage::default();
+        image
+            .descriptors
+            .push((0xffff0000, 0x10000, SEV_META_DESC_TYPE_MEM));
+        assert!(parse(&image.build()).is_ok());
+        image.descriptors.last_mut().unwrap().1 = 0x11000;
+        let err = parse(&image.build()).unwrap_err().to_string();
+        assert!(err.contains("extends beyond 4GB"), "{err}");
     }
 
-    pub fn get_fw_info(&self) -> OvmfFwInfo {
-        self.fw_info
+    #[test]
+    fn kernel_hashes_end_at_4gb() {
+        let mut image = TestImage::default();
+        image
+            .descriptors
+            .push((0xffffe000, 0x2000, SEV_META_DESC_TYPE_KERNEL_HASHES));
+        let mut firmware = OvmfFirmware::from_bytes(
+            image.build(),
+            &[(Platform::SevSnp, 1)],
+            false,
+            Some(0x1000000),
+            None,
+            SnpPageOptions::default(),
+        )
+        .unwrap();
+        let gpas: Vec<u64> = firmware
+            .take_directives()
+            .iter()
+            .filter_map(|directive| match directive {
+                IgvmDirectiveHeader::PageData { gpa, .. } if *gpa >= 0xffffe000 => Some(*gpa),
+                _ => None,
+            })
+            .collect();
+        assert_eq!(gpas, [0xffffe000, 0xfffff000]);
     }
 }
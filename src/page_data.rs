@@ -0,0 +1,180 @@
+// SPDX-License-Identifier: MIT OR Apache-2.0
+//
+// Copyright (c) 2024 SUSE LLC
+//
+// Author: Roy Hopkins <roy.hopkins@suse.com>
+
+use std::collections::HashMap;
+use std::error::Error;
+use std::fs;
+use std::ops::Range;
+
+use igvm::IgvmDirectiveHeader;
+use igvm_defs::{IgvmPageDataFlags, IgvmPageDataType, PAGE_SIZE_4K};
+
+const PAGE_SIZE_2M: u64 = 0x200000;
+
+/// Build page directives that populate the given data into guest memory,
+/// starting at `gpa` and split into 4K pages.
+pub fn build_page_data(data: &[u8], gpa: u64, compatibility_mask: u32) -> Vec<IgvmDirectiveHeader> {
+    let mut gpa = gpa;
+    let mut directives = Vec::<IgvmDirectiveHeader>::new();
+    for page_data in data.chunks(PAGE_SIZE_4K as usize) {
+        directives.push(IgvmDirectiveHeader::PageData {
+            gpa,
+            compatibility_mask,
+            flags: IgvmPageDataFlags::new(),
+            data_type: IgvmPageDataType::NORMAL,
+            data: page_data.to_vec(),
+        });
+        gpa += PAGE_SIZE_4K;
+    }
+    directives
+}
+
+/// Remove the contents of pages that are entirely zero. The pages are dropped
+/// for the platforms in `skip_mask`, where the loader zero-initializes guest
+/// memory, and are kept as zero pages without file data for the other
+/// platforms, which must still accept or encrypt them.
+pub fn sparse_page_data(
+    directives: Vec<IgvmDirectiveHeader>,
+    skip_mask: u32,
+) -> Vec<IgvmDirectiveHeader> {
+    directives
+        .into_iter()
+        .filter_map(|directive| match directive {
+            IgvmDirectiveHeader::PageData {
+                gpa,
+                compatibility_mask,
+                flags,
+                data_type: IgvmPageDataType::NORMAL,
+                data,
+            } if !data.is_empty() && data.iter().all(|byte| *byte == 0) => {
+                let compatibility_mask = compatibility_mask & !skip_mask;
+                (compatibility_mask != 0).then(|| IgvmDirectiveHeader::PageData {
+                    gpa,
+                    compatibility_mask,
+                    flags,
+                    data_type: IgvmPageDataType::NORMAL,
+                    data: vec![],
+                })
+            }
+            directive => Some(directive),
+        })
+        .collect()
+}
+
+/// Read an image that is to be placed at a page aligned `gpa`, returning its
+/// contents and the range of guest memory it occupies.
+pub fn load_image(
+    filename: &String,
+    gpa: u64,
+    description: &str,
+) -> Result<(Vec<u8>, Range<u64>), Box<dyn Error>> {
+    if gpa & (PAGE_SIZE_4K - 1) != 0 {
+        return Err(format!("The {description} GPA {gpa:#X} is not page aligned").into());
+    }
+    let data = fs::read(filename).inspect_err(|_| {
+        eprintln!("Failed to open {description} file {}", filename);
+    })?;
+    let end = gpa
+        .checked_add(data.len() as u64)
+        .ok_or(format!("The {description} does not fit at GPA {gpa:#X}"))?;
+    Ok((data, gpa..end))
+}
+
+/// Return the ranges of guest memory populated by the page data and parameter
+/// insert directives that apply to any platform in `compatibility_mask`.
+pub fn populated_ranges(
+    directives: &[IgvmDirectiveHeader],
+    compatibility_mask: u32,
+) -> Vec<Range<u64>> {
+    let area_sizes: HashMap<u32, u64> = directives
+        .iter()
+        .filter_map(|directive| match directive {
+            IgvmDirectiveHeader::ParameterArea {
+                number_of_bytes,
+                parameter_area_index,
+                ..
+            } => Some((*parameter_area_index, *number_of_bytes)),
+            _ => None,
+        })
+        .collect();
+    directives
+        .iter()
+        .filter_map(|directive| match directive {
+            IgvmDirectiveHeader::PageData {
+                gpa,
+                compatibility_mask: mask,
+                flags,
+                ..
+            } if mask & compatibility_mask != 0 => {
+                let size = if flags.is_2mb_page() {
+                    PAGE_SIZE_2M
+                } else {
+                    PAGE_SIZE_4K
+                };
+                Some(*gpa..gpa.saturating_add(size))
+            }
+            IgvmDirectiveHeader::ParameterInsert(insert)
+                if insert.compatibility_mask & compatibility_mask != 0 =>
+            {
+                let size = area_sizes
+                    .get(&insert.parameter_area_index)
+                    .copied()
+                    .unwrap_or(PAGE_SIZE_4K);
+                Some(insert.gpa..insert.gpa.saturating_add(size))
+            }
+            _ => None,
+        })
+        .collect()
+}
+
+pub fn overlaps(a: &Range<u64>, b: &Range<u64>) -> bool {
+    a.start < b.end && b.start < a.end
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use igvm_defs::IGVM_VHS_PARAMETER_INSERT;
+
+    fn page(gpa: u64, is_2mb_page: bool) -> IgvmDirectiveHeader {
+        IgvmDirectiveHeader::PageData {
+            gpa,
+            compatibility_mask: 1,
+            flags: IgvmPageDataFlags::new().with_is_2mb_page(is_2mb_page),
+            data_type: IgvmPageDataType::NORMAL,
+            data: vec![],
+        }
+    }
+
+    #[test]
+    fn populated_ranges_include_large_pages_and_parameter_areas() {
+        let directives = vec![
+            page(0x200000, true),
+            page(0x1000, false),
+            IgvmDirectiveHeader::ParameterArea {
+                number_of_bytes: 0x2000,
+                parameter_area_index: 0,
+                initial_data: vec![],
+            },
+            IgvmDirectiveHeader::ParameterInsert(IGVM_VHS_PARAMETER_INSERT {
+                gpa: 0x10000,
+                compatibility_mask: 1,
+                parameter_area_index: 0,
+            }),
+        ];
+        assert_eq!(
+            populated_ranges(&directives, 1),
+            vec![0x200000..0x400000, 0x1000..0x2000, 0x10000..0x12000]
+        );
+        assert!(populated_ranges(&directives, 2).is_empty());
+    }
+
+    #[test]
+    fn overlapping_ranges() {
+        assert!(overlaps(&(0x1000..0x3000), &(0x2000..0x3000)));
+        assert!(!overlaps(&(0x1000..0x2000), &(0x2000..0x3000)));
+    }
+}
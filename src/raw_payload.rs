@@ -0,0 +1,68 @@
+// SPDX-License-Identifier: MIT OR Apache-2.0
+//
+// Copyright (c) 2024 SUSE LLC
+//
+// Author: Roy Hopkins <roy.hopkins@suse.com>
+
+use std::error::Error;
+
+use igvm::IgvmDirectiveHeader;
+use igvm_defs::PAGE_SIZE_4K;
+
+use crate::firmware::FirmwareSource;
+use crate::ovmf_firmware::OvmfFwInfo;
+use crate::page_data::{build_page_data, load_image};
+
+/// A flat binary loaded at a guest physical address in place of a firmware.
+pub struct RawPayload {
+    data: Vec<u8>,
+    fw_info: OvmfFwInfo,
+    directives: Vec<IgvmDirectiveHeader>,
+}
+
+impl RawPayload {
+    /// Load the binary, padding it with zeros to a whole number of pages.
+    /// There is no metadata so the firmware information only describes the
+    /// range of the binary.
+    pub fn parse(
+        filename: &String,
+        gpa: u64,
+        compatibility_mask: u32,
+    ) -> Result<Self, Box<dyn Error>> {
+        let (mut data, range) = load_image(filename, gpa, "raw payload")?;
+        if range.end > 0x100000000 {
+            return Err(format!(
+                "The raw payload at {:#X}-{:#X} extends beyond 4GB",
+                range.start, range.end
+            )
+            .into());
+        }
+        data.resize(data.len().next_multiple_of(PAGE_SIZE_4K as usize), 0);
+
+        let fw_info = OvmfFwInfo {
+            start: gpa as u32,
+            size: data.len() as u32,
+            ..Default::default()
+        };
+        let directives = build_page_data(&data, gpa, compatibility_mask);
+        Ok(Self {
+            data,
+            fw_info,
+            directives,
+        })
+    }
+}
+
+impl FirmwareSource for RawPayload {
+    fn take_directives(&mut self) -> Vec<IgvmDirectiveHeader> {
+        std::mem::take(&mut self.directives)
+    }
+
+    fn fw_info(&self) -> &OvmfFwInfo {
+        &self.fw_info
+    }
+
+    fn data(&self) -> &[u8] {
+        &self.data
+    }
+}
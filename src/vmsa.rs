@@ -6,98 +6,362 @@
 
 use std::error::Error;
 
-use igvm::snp_defs::SevVmsa;
+use igvm::snp_defs::{SevFeatures, SevVmsa};
 use igvm::IgvmDirectiveHeader;
+use igvm_defs::IgvmNativeVpContextX64;
 use zerocopy::FromZeroes;
 
-use crate::cmd_options::Platform;
+use crate::cmd_options::{Platform, SevFeature};
+use crate::ovmf_firmware::OvmfFwInfo;
+
+// Initial control register values for the reset state of a processor.
+pub const DEFAULT_CR0: u64 = 0x10;
+const DEFAULT_CR4: u64 = 0x40;
+pub const DEFAULT_EFER: u64 = EFER_SVME;
+pub const DEFAULT_XCR0: u64 = XCR0_X87;
+
+const CR0_PE: u64 = 1 << 0;
+const CR0_NW: u64 = 1 << 29;
+const CR0_CD: u64 = 1 << 30;
+const CR0_PG: u64 = 1 << 31;
+const CR4_PAE: u64 = 1 << 5;
+const CR4_OSXSAVE: u64 = 1 << 18;
+const EFER_LME: u64 = 1 << 8;
+const EFER_LMA: u64 = 1 << 10;
+const EFER_SVME: u64 = 1 << 12;
+const XCR0_X87: u64 = 1 << 0;
+const XCR0_SSE: u64 = 1 << 1;
+const XCR0_AVX: u64 = 1 << 2;
+
+// Segment attributes in the packed format used by the VMSA, where bits 0-7 are
+// the descriptor access byte and bits 8-11 are the AVL, L, D/B and G flags (AMD
+// APM volume 2, appendix B).
+const SEG_ATTR_CODE: u16 = 0x9b;
+const SEG_ATTR_DATA: u16 = 0x93;
+const SEG_ATTR_LDT: u16 = 0x82;
+const SEG_ATTR_TSS_BUSY: u16 = 0x8b;
+const SEG_ATTR_L: u16 = 1 << 9;
+const SEG_ATTR_DB: u16 = 1 << 10;
+const SEG_ATTR_G: u16 = 1 << 11;
+const SEG_ATTR_CODE64: u16 = SEG_ATTR_CODE | SEG_ATTR_L | SEG_ATTR_G;
+const SEG_ATTR_DATA_FLAT: u16 = SEG_ATTR_DATA | SEG_ATTR_DB | SEG_ATTR_G;
+
+// Selectors used for a long mode BSP, matching the layout of a typical flat
+// GDT with the code descriptor first.
+const LONG_MODE_CS_SELECTOR: u16 = 0x08;
+const LONG_MODE_SS_SELECTOR: u16 = 0x10;
+
+// Reset values of the x87 control word and MXCSR.
+const X87_FCW_RESET: u16 = 0x37f;
+const MXCSR_RESET: u32 = 0x1f80;
+
+// Bits that are defined in each control register. Setting any other bit
+// causes VMRUN to fail.
+const CR0_DEFINED: u64 = 0xe005003f;
+const CR4_DEFINED: u64 = 0x01ff7fff;
+const EFER_DEFINED: u64 = 0xfd01;
+
+/// State that is common to the VMSAs of all processors.
+pub struct VmsaOptions<'a> {
+    pub features: &'a [SevFeature],
+    pub vmpl: u8,
+    pub cr0: u64,
+    pub cr4: u64,
+    pub efer: u64,
+    pub xcr0: u64,
+}
+
+/// State that only applies to the VMSA of the BSP.
+pub struct BspOptions<'a> {
+    pub reset_addr: u32,
+    pub registers: &'a [(String, u64)],
+    pub rsp: u64,
+    /// Base and limit of the initial GDT and IDT.
+    pub gdtr: Option<(u64, u32)>,
+    pub idtr: Option<(u64, u32)>,
+    /// The page table root when the BSP starts in long mode.
+    pub long_mode_cr3: Option<u64>,
+}
+
+/// The initial value of CR4 when it is not overridden. Extended state in XCR0
+/// can only be used when CR4.OSXSAVE is set, so it is enabled when XCR0
+/// includes any state other than x87.
+pub fn default_cr4(xcr0: u64) -> u64 {
+    if xcr0 & !XCR0_X87 != 0 {
+        DEFAULT_CR4 | CR4_OSXSAVE
+    } else {
+        DEFAULT_CR4
+    }
+}
+
+/// Check the architectural constraints on the value of XCR0.
+pub fn validate_xcr0(xcr0: u64) -> Result<(), Box<dyn Error>> {
+    if xcr0 & XCR0_X87 == 0 {
+        return Err(format!("XCR0 {xcr0:#X} must have the x87 bit set").into());
+    }
+    if xcr0 & XCR0_AVX != 0 && xcr0 & XCR0_SSE == 0 {
+        return Err(format!("XCR0 {xcr0:#X} enables AVX state without SSE state").into());
+    }
+    Ok(())
+}
+
+/// Check that the initial control register values are consistent so the
+/// processor does not fault as soon as it starts.
+pub fn validate_control_registers(
+    cr0: u64,
+    cr4: u64,
+    efer: u64,
+    xcr0: u64,
+) -> Result<(), Box<dyn Error>> {
+    if cr0 & !CR0_DEFINED != 0 {
+        return Err(format!("CR0 {cr0:#X} sets reserved bits {:#X}", cr0 & !CR0_DEFINED).into());
+    }
+    if cr4 & !CR4_DEFINED != 0 {
+        return Err(format!("CR4 {cr4:#X} sets reserved bits {:#X}", cr4 & !CR4_DEFINED).into());
+    }
+    if efer & !EFER_DEFINED != 0 {
+        return Err(format!(
+            "EFER {efer:#X} sets reserved bits {:#X}",
+            efer & !EFER_DEFINED
+        )
+        .into());
+    }
+    if cr0 & CR0_NW != 0 && cr0 & CR0_CD == 0 {
+        return Err(format!("CR0 {cr0:#X} sets NW without CD").into());
+    }
+    if cr0 & CR0_PG != 0 && cr0 & CR0_PE == 0 {
+        return Err(format!("CR0 {cr0:#X} enables paging without protected mode").into());
+    }
+    if efer & EFER_SVME == 0 {
+        return Err(format!("EFER {efer:#X} must have SVME set for an SEV-ES guest").into());
+    }
+    // XCR0 only controls the x87 state unless XSAVE is enabled.
+    if xcr0 & !XCR0_X87 != 0 && cr4 & CR4_OSXSAVE == 0 {
+        return Err(format!(
+            "XCR0 {xcr0:#X} enables extended state but CR4 {cr4:#X} does not set OSXSAVE"
+        )
+        .into());
+    }
+    let long_mode = efer & EFER_LME != 0 && cr0 & CR0_PG != 0;
+    if long_mode && cr4 & CR4_PAE == 0 {
+        return Err(format!("Long mode requires CR4.PAE but CR4 is {cr4:#X}").into());
+    }
+    if long_mode != (efer & EFER_LMA != 0) {
+        return Err(format!(
+            "EFER.LMA in {efer:#X} must only be set when EFER.LME and CR0.PG are set"
+        )
+        .into());
+    }
+    Ok(())
+}
+
+/// The SEV features for the VMSA of the given platform. Features that are
+/// only supported by SEV-SNP are not applied to SEV-ES.
+pub fn sev_features(platform: Platform, features: &[SevFeature]) -> SevFeatures {
+    let mut sev_features = SevFeatures::new();
+    if let Platform::SevSnp = platform {
+        sev_features.set_snp(true);
+    }
+    for feature in features {
+        if feature.snp_only() && platform != Platform::SevSnp {
+            continue;
+        }
+        match feature {
+            SevFeature::ReflectVc => sev_features.set_reflect_vc(true),
+            SevFeature::RestrictInjection => sev_features.set_restrict_injection(true),
+            SevFeature::AlternateInjection => sev_features.set_alternate_injection(true),
+            SevFeature::DebugSwap => sev_features.set_debug_swap(true),
+            SevFeature::PreventHostIbs => sev_features.set_prevent_host_ibs(true),
+            SevFeature::SnpBtbIsolation => sev_features.set_snp_btb_isolation(true),
+            SevFeature::SecureTsc => sev_features.set_secure_tsc(true),
+            SevFeature::VmsaRegProtection => sev_features.set_vmsa_reg_protection(true),
+        }
+    }
+    sev_features
+}
+
+fn construct_vmsa(
+    reset_addr: u32,
+    platform: Platform,
+    options: &VmsaOptions,
+) -> Result<Box<SevVmsa>, Box<dyn Error>> {
+    validate_control_registers(options.cr0, options.cr4, options.efer, options.xcr0)?;
 
-fn construct_vmsa(reset_addr: u32, platform: Platform) -> Result<Box<SevVmsa>, Box<dyn Error>> {
     let mut vmsa_box = SevVmsa::new_box_zeroed();
     let vmsa = vmsa_box.as_mut();
 
     // Establish CS as a 32-bit code selector.
     vmsa.cs.selector = 0xf000;
-    vmsa.cs.base = reset_addr as u64 & 0xffff0000;
     vmsa.cs.limit = 0xffff;
-    vmsa.cs.attrib = 0x9b;
+    vmsa.cs.attrib = SEG_ATTR_CODE;
 
     vmsa.ds.selector = 0;
     vmsa.ds.base = 0;
     vmsa.ds.limit = 0xffff;
-    vmsa.ds.attrib = 0x93;
+    vmsa.ds.attrib = SEG_ATTR_DATA;
     vmsa.es.selector = 0;
     vmsa.es.base = 0;
     vmsa.es.limit = 0xffff;
-    vmsa.es.attrib = 0x93;
+    vmsa.es.attrib = SEG_ATTR_DATA;
     vmsa.fs.selector = 0;
     vmsa.fs.base = 0;
     vmsa.fs.limit = 0xffff;
-    vmsa.fs.attrib = 0x93;
+    vmsa.fs.attrib = SEG_ATTR_DATA;
     vmsa.gs.selector = 0;
     vmsa.gs.base = 0;
     vmsa.gs.limit = 0xffff;
-    vmsa.gs.attrib = 0x93;
+    vmsa.gs.attrib = SEG_ATTR_DATA;
     vmsa.ss.selector = 0;
     vmsa.ss.base = 0;
     vmsa.ss.limit = 0xffff;
-    vmsa.ss.attrib = 0x93;
+    vmsa.ss.attrib = SEG_ATTR_DATA;
 
     vmsa.tr.selector = 0;
     vmsa.tr.base = 0;
     vmsa.tr.limit = 0xffff;
-    vmsa.tr.attrib = 0x8b;
+    vmsa.tr.attrib = SEG_ATTR_TSS_BUSY;
     vmsa.ldtr.selector = 0;
     vmsa.ldtr.base = 0;
     vmsa.ldtr.limit = 0xffff;
-    vmsa.ldtr.attrib = 0x82;
+    vmsa.ldtr.attrib = SEG_ATTR_LDT;
 
     vmsa.dr6 = 0xffff0ff0;
     vmsa.dr7 = 0x400;
 
-    //vmsa.cr0 = 0x60000010;
-    vmsa.cr0 = 0x10;
-    vmsa.cr4 = 0x40;
-    vmsa.xcr0 = 1;
+    vmsa.cr0 = options.cr0;
+    vmsa.cr4 = options.cr4;
+    vmsa.xcr0 = options.xcr0;
 
-    vmsa.rip = reset_addr as u64 & 0xffff;
+    set_reset_addr(vmsa, reset_addr);
     vmsa.rflags = 2;
     vmsa.pat = 0x0007040600070406;
-    vmsa.efer = 0x1000;
+    vmsa.efer = options.efer;
 
-    vmsa.x87_fcw = 0x37f;
-    vmsa.mxcsr = 0x1f80;
+    vmsa.x87_fcw = X87_FCW_RESET;
+    vmsa.mxcsr = MXCSR_RESET;
 
+    vmsa.sev_features = sev_features(platform, options.features);
+
+    // VMPLs only exist on SEV-SNP.
     if let Platform::SevSnp = platform {
-        vmsa.sev_features.set_snp(true);
+        vmsa.vmpl = options.vmpl;
     }
 
     Ok(vmsa_box)
 }
 
-pub fn construct_ap_vmsa(
+/// The reset address is split between the CS base and RIP.
+fn set_reset_addr(vmsa: &mut SevVmsa, reset_addr: u32) {
+    vmsa.cs.base = reset_addr as u64 & 0xffff0000;
+    vmsa.rip = reset_addr as u64 & 0xffff;
+}
+
+/// Start a processor in 64-bit long mode at `reset_addr` with paging enabled
+/// using the page tables at `cr3`. The code and stack segments are flat so the
+/// reset address is used directly as RIP.
+fn set_long_mode(vmsa: &mut SevVmsa, reset_addr: u32, cr3: u64) -> Result<(), Box<dyn Error>> {
+    vmsa.cr0 |= CR0_PE | CR0_PG;
+    vmsa.cr3 = cr3;
+    vmsa.cr4 |= CR4_PAE;
+    vmsa.efer |= EFER_LME | EFER_LMA;
+    validate_control_registers(vmsa.cr0, vmsa.cr4, vmsa.efer, vmsa.xcr0)?;
+
+    vmsa.cs.selector = LONG_MODE_CS_SELECTOR;
+    vmsa.cs.base = 0;
+    vmsa.cs.limit = 0xffffffff;
+    vmsa.cs.attrib = SEG_ATTR_CODE64;
+    vmsa.ss.selector = LONG_MODE_SS_SELECTOR;
+    vmsa.ss.base = 0;
+    vmsa.ss.limit = 0xffffffff;
+    vmsa.ss.attrib = SEG_ATTR_DATA_FLAT;
+    vmsa.rip = reset_addr as u64;
+    Ok(())
+}
+
+fn set_gp_register(vmsa: &mut SevVmsa, name: &str, value: u64) -> Result<(), Box<dyn Error>> {
+    let register = match name {
+        "rax" => &mut vmsa.rax,
+        "rbx" => &mut vmsa.rbx,
+        "rcx" => &mut vmsa.rcx,
+        "rdx" => &mut vmsa.rdx,
+        "rsi" => &mut vmsa.rsi,
+        "rdi" => &mut vmsa.rdi,
+        "rbp" => &mut vmsa.rbp,
+        "rsp" => &mut vmsa.rsp,
+        "r8" => &mut vmsa.r8,
+        "r9" => &mut vmsa.r9,
+        "r10" => &mut vmsa.r10,
+        "r11" => &mut vmsa.r11,
+        "r12" => &mut vmsa.r12,
+        "r13" => &mut vmsa.r13,
+        "r14" => &mut vmsa.r14,
+        "r15" => &mut vmsa.r15,
+        _ => {
+            return Err(format!(
+                "Unknown register '{name}'. Valid registers are rax, rbx, rcx, rdx, rsi, rdi, rbp, rsp and r8-r15"
+            )
+            .into())
+        }
+    };
+    *register = value;
+    Ok(())
+}
+
+/// Construct the VMSAs for the application processors 1..cpucount. IGVM has
+/// no way to share a VP context between processors so each one needs its own
+/// directive. All APs start at the SEV-ES reset address of the firmware unless
+/// an explicit AP reset address is given, so the VMSA is built once and copied.
+pub fn construct_ap_vmsas(
     gpa_start: u64,
     compatibility_mask: u32,
     platform: Platform,
-    reset_addr: u32,
-    vp_index: u16,
-) -> Result<IgvmDirectiveHeader, Box<dyn Error>> {
-    let vmsa = construct_vmsa(reset_addr, platform)?;
+    options: &VmsaOptions,
+    fw_info: &OvmfFwInfo,
+    cpucount: u16,
+    ap_reset: Option<u32>,
+) -> Result<Vec<IgvmDirectiveHeader>, Box<dyn Error>> {
+    // Without a reset address every AP would start executing at zero.
+    if cpucount > 1 && ap_reset.is_none() && fw_info.reset_addr == 0 {
+        return Err(
+            "The firmware does not define an SEV-ES reset block so the APs have no reset address. Use --ap-reset to specify one or set --cpucount to 1"
+                .into(),
+        );
+    }
+    let template = construct_vmsa(ap_reset.unwrap_or(fw_info.reset_addr), platform, options)?;
 
-    Ok(IgvmDirectiveHeader::SnpVpContext {
-        gpa: gpa_start,
-        compatibility_mask,
-        vp_index,
-        vmsa,
-    })
+    Ok((1..cpucount)
+        .map(|vp_index| IgvmDirectiveHeader::SnpVpContext {
+            gpa: gpa_start,
+            compatibility_mask,
+            vp_index,
+            vmsa: template.clone(),
+        })
+        .collect())
 }
 
 pub fn construct_bsp_vmsa(
     gpa_start: u64,
     compatibility_mask: u32,
     platform: Platform,
+    options: &VmsaOptions,
+    bsp: &BspOptions,
 ) -> Result<IgvmDirectiveHeader, Box<dyn Error>> {
-    let vmsa = construct_vmsa(0xfffffff0u32, platform)?;
+    let mut vmsa = construct_vmsa(bsp.reset_addr, platform, options)?;
+    if let Some(cr3) = bsp.long_mode_cr3 {
+        set_long_mode(&mut vmsa, bsp.reset_addr, cr3)?;
+    }
+    vmsa.rsp = bsp.rsp;
+    for (name, value) in bsp.registers {
+        set_gp_register(&mut vmsa, name, *value)?;
+    }
+    if let Some((base, limit)) = bsp.gdtr {
+        vmsa.gdtr.base = base;
+        vmsa.gdtr.limit = limit;
+    }
+    if let Some((base, limit)) = bsp.idtr {
+        vmsa.idtr.base = base;
+        vmsa.idtr.limit = limit;
+    }
 
     Ok(IgvmDirectiveHeader::SnpVpContext {
         gpa: gpa_start,
@@ -106,3 +370,110 @@ pub fn construct_bsp_vmsa(
         vmsa,
     })
 }
+
+/// Construct the initial register state of the BSP for the native platform,
+/// which the loader uses to start the BSP at the reset address in real mode.
+pub fn construct_native_bsp_context(
+    compatibility_mask: u32,
+    reset_addr: u32,
+    rsp: u64,
+) -> IgvmDirectiveHeader {
+    let mut context = IgvmNativeVpContextX64::new_box_zeroed();
+
+    context.code_selector = 0xf000;
+    context.code_base = reset_addr & 0xffff0000;
+    context.code_limit = 0xffff;
+    context.code_attributes = SEG_ATTR_CODE;
+    context.rip = reset_addr as u64 & 0xffff;
+
+    context.data_selector = 0;
+    context.data_base = 0;
+    context.data_limit = 0xffff;
+    context.data_attributes = SEG_ATTR_DATA;
+
+    context.gdtr_limit = 0xffff;
+    context.idtr_limit = 0xffff;
+
+    context.cr0 = DEFAULT_CR0;
+    context.rflags = 2;
+    context.rsp = rsp;
+
+    IgvmDirectiveHeader::X64NativeVpContext {
+        compatibility_mask,
+        vp_index: 0,
+        context,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn vmsa_options() -> VmsaOptions<'static> {
+        VmsaOptions {
+            features: &[],
+            vmpl: 0,
+            cr0: DEFAULT_CR0,
+            cr4: default_cr4(DEFAULT_XCR0),
+            efer: DEFAULT_EFER,
+            xcr0: DEFAULT_XCR0,
+        }
+    }
+
+    fn bsp_options() -> BspOptions<'static> {
+        BspOptions {
+            reset_addr: 0xfffffff0,
+            registers: &[],
+            rsp: 0,
+            gdtr: None,
+            idtr: None,
+            long_mode_cr3: None,
+        }
+    }
+
+    fn bsp_vmsa(options: &VmsaOptions, bsp: &BspOptions) -> Box<SevVmsa> {
+        match construct_bsp_vmsa(0x1000, 1, Platform::SevSnp, options, bsp).unwrap() {
+            IgvmDirectiveHeader::SnpVpContext { vmsa, .. } => vmsa,
+            _ => panic!("expected an SNP VP context"),
+        }
+    }
+
+    #[test]
+    fn descriptor_tables() {
+        let vmsa = bsp_vmsa(&vmsa_options(), &bsp_options());
+        assert_eq!((vmsa.gdtr.base, vmsa.gdtr.limit), (0, 0));
+        assert_eq!((vmsa.idtr.base, vmsa.idtr.limit), (0, 0));
+
+        let vmsa = bsp_vmsa(
+            &vmsa_options(),
+            &BspOptions {
+                gdtr: Some((0x7000, 0x27)),
+                idtr: Some((0x8000, 0xfff)),
+                ..bsp_options()
+            },
+        );
+        assert_eq!((vmsa.gdtr.base, vmsa.gdtr.limit), (0x7000, 0x27));
+        assert_eq!((vmsa.idtr.base, vmsa.idtr.limit), (0x8000, 0xfff));
+    }
+
+    #[test]
+    fn xcr0_requires_osxsave() {
+        let xcr0 = XCR0_X87 | XCR0_SSE | XCR0_AVX;
+        assert!(validate_control_registers(
+            DEFAULT_CR0,
+            default_cr4(DEFAULT_XCR0),
+            DEFAULT_EFER,
+            DEFAULT_XCR0
+        )
+        .is_ok());
+        assert_eq!(default_cr4(DEFAULT_XCR0) & CR4_OSXSAVE, 0);
+        assert_ne!(default_cr4(xcr0) & CR4_OSXSAVE, 0);
+        assert!(
+            validate_control_registers(DEFAULT_CR0, default_cr4(xcr0), DEFAULT_EFER, xcr0).is_ok()
+        );
+        let err = validate_control_registers(DEFAULT_CR0, DEFAULT_CR4, DEFAULT_EFER, xcr0)
+            .unwrap_err()
+            .to_string();
+        assert!(err.contains("does not set OSXSAVE"), "{err}");
+    }
+}
@@ -0,0 +1,163 @@
+// SPDX-License-Identifier: MIT OR Apache-2.0
+//
+// Copyright (c) 2024 SUSE LLC
+//
+// Author: Roy Hopkins <roy.hopkins@suse.com>
+
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::process::Command;
+
+use igvm::{IgvmDirectiveHeader, IgvmFile, IgvmInitializationHeader, IgvmPlatformHeader};
+use igvm_defs::IgvmPlatformType;
+
+use common::TestImage;
+
+mod common;
+
+/// A directory for the files of one test, removed when the test completes.
+struct TestDir(PathBuf);
+
+impl TestDir {
+    fn new(name: &str) -> Self {
+        let dir =
+            std::env::temp_dir().join(format!("buildigvm-test-{}-{name}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        Self(dir)
+    }
+
+    fn path(&self, filename: &str) -> PathBuf {
+        self.0.join(filename)
+    }
+}
+
+impl Drop for TestDir {
+    fn drop(&mut self) {
+        let _ = fs::remove_dir_all(&self.0);
+    }
+}
+
+fn buildigvm(firmware: &Path, output: &Path, args: &[&str]) {
+    let result = Command::new(env!("CARGO_BIN_EXE_buildigvm"))
+        .arg("build")
+        .arg("--firmware")
+        .arg(firmware)
+        .arg("--output")
+        .arg(output)
+        .args(args)
+        .output()
+        .unwrap();
+    assert!(
+        result.status.success(),
+        "buildigvm {args:?} failed: {}",
+        String::from_utf8_lossy(&result.stderr)
+    );
+}
+
+struct Expected {
+    platform: &'static str,
+    platform_type: IgvmPlatformType,
+    initialization: usize,
+    pages: usize,
+    vp_contexts: usize,
+}
+
+#[test]
+fn build_each_platform() {
+    let dir = TestDir::new("platforms");
+    let firmware = dir.path("OVMF.fd");
+    let image = TestImage::default();
+    fs::write(&firmware, image.build()).unwrap();
+    let firmware_pages = image.size / 0x1000;
+
+    for expected in [
+        Expected {
+            platform: "native",
+            platform_type: IgvmPlatformType::NATIVE,
+            initialization: 1,
+            pages: firmware_pages,
+            vp_contexts: 0,
+        },
+        Expected {
+            platform: "sev",
+            platform_type: IgvmPlatformType::SEV,
+            initialization: 1,
+            pages: firmware_pages,
+            vp_contexts: 0,
+        },
+        Expected {
+            platform: "sev-es",
+            platform_type: IgvmPlatformType::SEV_ES,
+            initialization: 1,
+            pages: firmware_pages,
+            vp_contexts: 2,
+        },
+        Expected {
+            platform: "sev-snp",
+            platform_type: IgvmPlatformType::SEV_SNP,
+            initialization: 1,
+            // The secrets, CPUID and calling area pages and 13 prevalidated
+            // pages.
+            pages: firmware_pages + 3 + 13,
+            vp_contexts: 2,
+        },
+        Expected {
+            platform: "tdx",
+            platform_type: IgvmPlatformType::TDX,
+            initialization: 0,
+            pages: firmware_pages,
+            vp_contexts: 0,
+        },
+    ] {
+        let output = dir.path(&format!("{}.igvm", expected.platform));
+        buildigvm(
+            &firmware,
+            &output,
+            &["--platform", expected.platform, "--cpucount", "2"],
+        );
+        let file = IgvmFile::new_from_binary(&fs::read(&output).unwrap(), None)
+            .unwrap_or_else(|e| panic!("{} output is invalid: {e}", expected.platform));
+
+        assert_eq!(file.platforms().len(), 1, "{}", expected.platform);
+        let IgvmPlatformHeader::SupportedPlatform(platform) = &file.platforms()[0];
+        assert_eq!(platform.platform_type, expected.platform_type);
+        assert_eq!(
+            file.initializations().len(),
+            expected.initialization,
+            "{}",
+            expected.platform
+        );
+        assert!(file
+            .initializations()
+            .iter()
+            .all(|header| matches!(header, IgvmInitializationHeader::GuestPolicy { .. })));
+        let pages = file
+            .directives()
+            .iter()
+            .filter(|directive| matches!(directive, IgvmDirectiveHeader::PageData { .. }))
+            .count();
+        assert_eq!(pages, expected.pages, "{}", expected.platform);
+        let vp_contexts = file
+            .directives()
+            .iter()
+            .filter(|directive| matches!(directive, IgvmDirectiveHeader::SnpVpContext { .. }))
+            .count();
+        assert_eq!(vp_contexts, expected.vp_contexts, "{}", expected.platform);
+    }
+}
+
+#[test]
+fn build_multiple_platforms() {
+    let dir = TestDir::new("multiple");
+    let firmware = dir.path("OVMF.fd");
+    fs::write(&firmware, TestImage::default().build()).unwrap();
+    let output = dir.path("all.igvm");
+    buildigvm(
+        &firmware,
+        &output,
+        &["-p", "native", "-p", "sev-es", "-p", "sev-snp"],
+    );
+    let file = IgvmFile::new_from_binary(&fs::read(&output).unwrap(), None).unwrap();
+    assert_eq!(file.platforms().len(), 3);
+    assert_eq!(file.initializations().len(), 3);
+}
@@ -0,0 +1,122 @@
+// SPDX-License-Identifier: MIT OR Apache-2.0
+//
+// Copyright (c) 2024 SUSE LLC
+//
+// Author: Roy Hopkins <roy.hopkins@suse.com>
+
+//! Synthetic OVMF firmware images shared by the unit tests and the
+//! integration tests. The layout is written out independently of the parser
+//! so that the tests check the parser against the format rather than itself.
+
+// Each test crate only uses part of the fixture.
+#![allow(dead_code)]
+
+use uuid::{uuid, Uuid};
+
+const OVMF_TABLE_FOOTER_GUID: Uuid = uuid!("96b582de-1fb2-45f7-baea-a366c55a082d");
+const OVMF_SEV_METADATA_GUID: Uuid = uuid!("dc886566-984a-4798-a75e-5585a7bf67cc");
+const SEV_INFO_BLOCK_GUID: Uuid = uuid!("00f771de-1a7e-4fcb-890e-68c77e2fb44e");
+
+const DESC_TYPE_MEM: u32 = 1;
+const DESC_TYPE_SECRETS: u32 = 2;
+const DESC_TYPE_CPUID: u32 = 3;
+const DESC_TYPE_CAA: u32 = 4;
+
+const FOOTER_OFFSET: usize = 32;
+const RESET_VECTOR_SIZE: usize = 16;
+const OPCODE_JMP_FAR: u8 = 0xea;
+
+pub const TEST_SECRETS_PAGE: u32 = 0x809000;
+pub const TEST_CPUID_PAGE: u32 = 0x80a000;
+pub const TEST_CAA_PAGE: u32 = 0x80b000;
+pub const TEST_RESET_ADDR: u32 = 0x80b004;
+
+/// Builds a synthetic OVMF image. The GUIDed table footer ends 32 bytes
+/// before the end of the image and wraps an SEV metadata table, which
+/// points at the metadata at offset 0x1000, and an SEV info block.
+pub struct TestImage {
+    pub size: usize,
+    pub descriptors: Vec<(u32, u32, u32)>,
+    pub metadata: bool,
+    pub reset_addr: Option<u32>,
+    pub padding: usize,
+}
+
+impl Default for TestImage {
+    fn default() -> Self {
+        Self {
+            size: 0x20000,
+            // Two prevalidated regions of 9 and 4 pages, and the secrets,
+            // CPUID and calling area pages.
+            descriptors: vec![
+                (0x800000, 0x9000, DESC_TYPE_MEM),
+                (TEST_SECRETS_PAGE, 0x1000, DESC_TYPE_SECRETS),
+                (TEST_CPUID_PAGE, 0x1000, DESC_TYPE_CPUID),
+                (TEST_CAA_PAGE, 0x1000, DESC_TYPE_CAA),
+                (0x80c000, 0x4000, DESC_TYPE_MEM),
+            ],
+            metadata: true,
+            reset_addr: Some(TEST_RESET_ADDR),
+            padding: 0,
+        }
+    }
+}
+
+/// Wrap a table payload with its size and GUID.
+pub fn table(payload: &[u8], guid: Uuid) -> Vec<u8> {
+    let mut table = payload.to_vec();
+    table.extend_from_slice(&((payload.len() + 18) as u16).to_le_bytes());
+    table.extend_from_slice(&guid.to_bytes_le());
+    table
+}
+
+impl TestImage {
+    pub const METADATA_OFFSET: usize = 0x1000;
+
+    /// The tables within the footer, ordered as they are laid out in the
+    /// image. The walk starts with the last table.
+    pub fn inner_tables(&self) -> Vec<u8> {
+        let mut tables = Vec::new();
+        if self.metadata {
+            let offset_from_end = (self.size - Self::METADATA_OFFSET) as u32;
+            tables.extend(table(
+                &offset_from_end.to_le_bytes(),
+                OVMF_SEV_METADATA_GUID,
+            ));
+        }
+        if let Some(reset_addr) = self.reset_addr {
+            tables.extend(table(&reset_addr.to_le_bytes(), SEV_INFO_BLOCK_GUID));
+        }
+        tables
+    }
+
+    pub fn build(&self) -> Vec<u8> {
+        self.build_with_footer(&table(&self.inner_tables(), OVMF_TABLE_FOOTER_GUID))
+    }
+
+    /// Build the image with the given bytes ending at the footer offset.
+    pub fn build_with_footer(&self, footer: &[u8]) -> Vec<u8> {
+        let mut data = vec![0u8; self.size];
+        for (i, page) in data.chunks_mut(0x1000).enumerate() {
+            page[0] = i as u8 | 1;
+        }
+        let mut metadata = Vec::new();
+        metadata.extend_from_slice(b"ASEV");
+        metadata.extend_from_slice(&(16 + 12 * self.descriptors.len() as u32).to_le_bytes());
+        metadata.extend_from_slice(&1u32.to_le_bytes());
+        metadata.extend_from_slice(&(self.descriptors.len() as u32).to_le_bytes());
+        for (base, len, metadata_type) in &self.descriptors {
+            metadata.extend_from_slice(&base.to_le_bytes());
+            metadata.extend_from_slice(&len.to_le_bytes());
+            metadata.extend_from_slice(&metadata_type.to_le_bytes());
+        }
+        data[Self::METADATA_OFFSET..Self::METADATA_OFFSET + metadata.len()]
+            .copy_from_slice(&metadata);
+        let end = self.size - FOOTER_OFFSET;
+        data[end - footer.len()..end].copy_from_slice(footer);
+        // A far jump in the reset vector.
+        data[self.size - RESET_VECTOR_SIZE] = OPCODE_JMP_FAR;
+        data.resize(self.size + self.padding, 0);
+        data
+    }
+}